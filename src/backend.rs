@@ -0,0 +1,502 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::config::OscConfig;
+use crate::emulator;
+use crate::machine::{self, MachineConfig};
+use crate::project::Project;
+use crate::qmp::{Monitor, Qmp};
+use crate::symbolize::Symbolizer;
+
+/// One boot of a kernel built by `build_iso`.
+pub struct Boot<'a> {
+    pub project: &'a Project,
+    /// The GRUB ISO for backends that boot from a cdrom.
+    pub iso: &'a Path,
+    /// The unstripped kernel ELF for backends that load the kernel directly.
+    pub kernel: &'a Path,
+    /// Directory the emulator runs in and keeps its generated files.
+    pub work_dir: &'a Path,
+    /// Arguments from the command line followed by the backend's
+    /// [`config_args`](RunnerBackend::config_args).
+    pub args: Vec<String>,
+    /// Typed machine settings, with the selected run profile applied.
+    pub machine: MachineConfig,
+}
+
+/// An emulator osc can boot kernels in, selected with `runner-backend`.
+///
+/// Every backend must put the guest's first serial port on the emulator's stdout,
+/// which osc passes through to the terminal and the symbolizer.
+pub trait RunnerBackend {
+    fn name(&self) -> &'static str;
+
+    /// The emulator command for `boot`, writing any config files it needs.
+    fn command(&self, boot: &Boot) -> Result<Command, Box<dyn Error>>;
+
+    /// The backend's own arguments from the osc settings, for a test boot or not.
+    fn config_args(&self, osc_config: &OscConfig, test: bool) -> Vec<String>;
+
+    /// Whether a test kernel can report its result as the emulator's exit code.
+    fn reports_exit_codes(&self) -> bool {
+        true
+    }
+
+    /// The exit code the guest asked for, if the backend can tell it apart from
+    /// the emulator's own status.
+    fn guest_exit_code(&self, status: ExitStatus) -> Option<i32> {
+        status.code()
+    }
+}
+
+pub fn backend(project: &Project) -> Result<Box<dyn RunnerBackend>, Box<dyn Error>> {
+    match project.osc_config.runner_backend.as_str() {
+        "qemu" => Ok(Box::new(Qemu)),
+        "bochs" => Ok(Box::new(Bochs)),
+        "cloud-hypervisor" => Ok(Box::new(CloudHypervisor)),
+        other => Err(format!(
+            "unknown runner-backend `{}`, expected qemu, bochs or cloud-hypervisor",
+            other
+        )
+        .into()),
+    }
+}
+
+/// Test kernels report their result as an exit code, so backends that cannot
+/// pass one on are refused rather than every test passing.
+pub fn check_tests(backend: &dyn RunnerBackend) -> Result<(), String> {
+    if backend.reports_exit_codes() {
+        return Ok(());
+    }
+    Err(format!(
+        "the {} backend cannot report a test kernel's exit code; run tests with runner-backend = \"qemu\"",
+        backend.name()
+    ))
+}
+
+pub struct Qemu;
+
+impl RunnerBackend for Qemu {
+    fn name(&self) -> &'static str {
+        "qemu"
+    }
+
+    fn command(&self, boot: &Boot) -> Result<Command, Box<dyn Error>> {
+        let mut args = boot.machine.qemu_args();
+        args.extend(boot.args.iter().cloned());
+        let conflicts = machine::conflicts(&args);
+        if !conflicts.is_empty() {
+            return Err(
+                format!("conflicting QEMU arguments:\n  {}", conflicts.join("\n  ")).into(),
+            );
+        }
+
+        let mut qemu = Command::new(emulator::emulator(boot.project)?);
+        qemu.arg("-cdrom").arg(boot.iso).current_dir(boot.work_dir);
+        let default_machine = boot
+            .project
+            .target
+            .as_ref()
+            .and_then(emulator::target_arch)
+            .and_then(|arch| emulator::default_machine(&arch));
+        if let Some(default_machine) = default_machine {
+            if boot.project.osc_config.emulator.is_none()
+                && !args.iter().any(|arg| arg == "-machine" || arg == "-M")
+            {
+                qemu.arg("-machine").arg(default_machine);
+            }
+        }
+        qemu.args(emulator::accel_args(boot.project, &args));
+        qemu.args(&args);
+        Ok(qemu)
+    }
+
+    fn config_args(&self, osc_config: &OscConfig, test: bool) -> Vec<String> {
+        if test {
+            osc_config.test_args.clone()
+        } else {
+            osc_config.run_args.clone()
+        }
+    }
+}
+
+/// Bochs booting the ISO from its cdrom drive. Its instruction-level debugger
+/// catches early-boot triple faults that QEMU resets through.
+pub struct Bochs;
+
+impl RunnerBackend for Bochs {
+    fn name(&self) -> &'static str {
+        "bochs"
+    }
+
+    fn command(&self, boot: &Boot) -> Result<Command, Box<dyn Error>> {
+        let bochsrc = boot.kernel.with_file_name("bochsrc.txt");
+        fs::write(&bochsrc, bochsrc_for(boot.iso, &bochsrc, &boot.machine)?)?;
+        let mut bochs = Command::new(
+            boot.project
+                .osc_config
+                .emulator
+                .clone()
+                .unwrap_or_else(|| String::from("bochs")),
+        );
+        bochs
+            .arg("-q")
+            .arg("-f")
+            .arg(&bochsrc)
+            .args(&boot.args)
+            .current_dir(boot.work_dir);
+        Ok(bochs)
+    }
+
+    fn config_args(&self, osc_config: &OscConfig, _test: bool) -> Vec<String> {
+        osc_config.bochs_args.clone()
+    }
+
+    /// Bochs has no device a guest can exit through with a status.
+    fn reports_exit_codes(&self) -> bool {
+        false
+    }
+}
+
+/// A bochsrc with the ISO as its cdrom, COM1 on stdout, the 0xE9 debug port
+/// enabled and the machine's `memory` and `smp`. Extra options can be given as
+/// `bochs-args`, which Bochs reads as bochsrc lines.
+fn bochsrc_for(iso: &Path, bochsrc: &Path, machine: &MachineConfig) -> Result<String, String> {
+    let log: PathBuf = bochsrc.with_file_name("bochs.log");
+    let megs = match &machine.memory {
+        Some(memory) => megabytes(memory)?,
+        None => 128,
+    };
+    let cpus = match machine.smp {
+        Some(smp) => format!("cpu: count={}\n", smp),
+        None => String::new(),
+    };
+    Ok(format!(
+        "megs: {}\n\
+         {}\
+         romimage: file=$BXSHARE/BIOS-bochs-latest\n\
+         vgaromimage: file=$BXSHARE/VGABIOS-lgpl-latest\n\
+         ata0-master: type=cdrom, path=\"{}\", status=inserted\n\
+         boot: cdrom\n\
+         com1: enabled=1, mode=file, dev=/dev/stdout\n\
+         port_e9_hack: enabled=1\n\
+         magic_break: enabled=1\n\
+         log: {}\n",
+        megs,
+        cpus,
+        iso.display(),
+        log.display()
+    ))
+}
+
+/// A QEMU-style memory size (`512`, `512M`, `2G`) in megabytes.
+fn megabytes(memory: &str) -> Result<u64, String> {
+    let (number, scale) = match memory.char_indices().last() {
+        Some((index, 'M' | 'm')) => (&memory[..index], 1),
+        Some((index, 'G' | 'g')) => (&memory[..index], 1024),
+        _ => (memory, 1),
+    };
+    number
+        .parse::<u64>()
+        .map(|number| number * scale)
+        .map_err(|_| format!("cannot read `memory = \"{}\"` as megabytes", memory))
+}
+
+/// Cloud Hypervisor loading the kernel ELF directly through its PVH entry point,
+/// since it cannot boot an ISO.
+pub struct CloudHypervisor;
+
+impl RunnerBackend for CloudHypervisor {
+    fn name(&self) -> &'static str {
+        "cloud-hypervisor"
+    }
+
+    fn command(&self, boot: &Boot) -> Result<Command, Box<dyn Error>> {
+        let mut hypervisor = Command::new(
+            boot.project
+                .osc_config
+                .emulator
+                .clone()
+                .unwrap_or_else(|| String::from("cloud-hypervisor")),
+        );
+        hypervisor
+            .arg("--kernel")
+            .arg(boot.kernel)
+            .arg("--serial")
+            .arg("tty")
+            .arg("--console")
+            .arg("off");
+        if let Some(memory) = &boot.machine.memory {
+            hypervisor
+                .arg("--memory")
+                .arg(format!("size={}M", megabytes(memory)?));
+        }
+        if let Some(smp) = boot.machine.smp {
+            hypervisor.arg("--cpus").arg(format!("boot={}", smp));
+        }
+        hypervisor.args(&boot.args).current_dir(boot.work_dir);
+        Ok(hypervisor)
+    }
+
+    fn config_args(&self, osc_config: &OscConfig, _test: bool) -> Vec<String> {
+        osc_config.cloud_hypervisor_args.clone()
+    }
+
+    /// The guest can only shut the VM down, which always exits with 0.
+    fn reports_exit_codes(&self) -> bool {
+        false
+    }
+}
+
+pub enum Outcome {
+    Exited(ExitStatus),
+    TimedOut,
+    /// The guest reported a panic through pvpanic and QEMU was stopped.
+    Panicked,
+    /// The guest reset itself, which for a kernel almost always means a triple
+    /// fault. Only reported when QEMU runs with `-no-reboot`.
+    Reset,
+}
+
+/// Runs the emulator with its stdout (the kernel's serial port) passed through osc to
+/// `output` so addresses in panic output can be symbolized right under the line that
+/// printed them. The emulator is killed if it is still running after `timeout`.
+///
+/// With a QMP `monitor` the timeout and panic hooks run before QEMU is stopped,
+/// and what they report is written to `output` after the serial output.
+pub fn run(
+    command: &mut Command,
+    symbolizer: Option<&Symbolizer>,
+    timeout: Option<Duration>,
+    monitor: Option<Monitor>,
+    output: &mut dyn Write,
+) -> io::Result<Outcome> {
+    let mut child = command.stdout(Stdio::piped()).spawn()?;
+    let mut serial = child.stdout.take().expect("Emulator stdout is not piped");
+    let child = Arc::new(Mutex::new(child));
+    let state = Arc::new(RunState::default());
+    // A timeout too long to fall on a representable instant never comes.
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    let watcher = match monitor {
+        Some(monitor) => Some(watch(monitor, child.clone(), state.clone(), deadline)),
+        None => deadline.map(|deadline| watchdog(child.clone(), state.clone(), deadline)),
+    };
+
+    let mut buffer = [0u8; 4096];
+    let mut line: Vec<u8> = Vec::new();
+    while let Ok(read) = serial.read(&mut buffer) {
+        if read == 0 {
+            break;
+        }
+        // Pass output through as it arrives so prompts without a newline still show up.
+        output.write_all(&buffer[..read]).ok();
+        output.flush().ok();
+        if let Some(symbolizer) = symbolizer {
+            for &byte in &buffer[..read] {
+                if byte != b'\n' {
+                    line.push(byte);
+                    continue;
+                }
+                for annotation in symbolizer.annotate(&String::from_utf8_lossy(&line)) {
+                    writeln!(output, "{}", annotation).ok();
+                }
+                line.clear();
+            }
+        }
+    }
+    let status = child.lock().unwrap().wait()?;
+    if let Some(watcher) = watcher {
+        watcher.join().ok();
+    }
+    for note in state.notes.lock().unwrap().iter() {
+        writeln!(output, "{}", note).ok();
+    }
+    if state.timed_out.load(Ordering::SeqCst) {
+        return Ok(Outcome::TimedOut);
+    }
+    if state.panicked.load(Ordering::SeqCst) {
+        return Ok(Outcome::Panicked);
+    }
+    if state.reset.load(Ordering::SeqCst) {
+        return Ok(Outcome::Reset);
+    }
+    Ok(Outcome::Exited(status))
+}
+
+/// What the watchdog or monitor thread saw while the emulator ran.
+#[derive(Default)]
+struct RunState {
+    timed_out: AtomicBool,
+    panicked: AtomicBool,
+    reset: AtomicBool,
+    notes: Mutex<Vec<String>>,
+}
+
+fn running(child: &Mutex<Child>) -> bool {
+    matches!(child.lock().unwrap().try_wait(), Ok(None))
+}
+
+fn watchdog(child: Arc<Mutex<Child>>, state: Arc<RunState>, deadline: Instant) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(100));
+        let mut child = child.lock().unwrap();
+        if !matches!(child.try_wait(), Ok(None)) {
+            return;
+        }
+        if Instant::now() >= deadline {
+            state.timed_out.store(true, Ordering::SeqCst);
+            child.kill().ok();
+            return;
+        }
+    })
+}
+
+/// The watchdog for QEMU runs with a QMP socket: it types the configured keys,
+/// and runs the timeout and panic hooks before stopping QEMU.
+fn watch(
+    monitor: Monitor,
+    child: Arc<Mutex<Child>>,
+    state: Arc<RunState>,
+    deadline: Option<Instant>,
+) -> JoinHandle<()> {
+    let started = Instant::now();
+    thread::spawn(move || {
+        let config = &monitor.config;
+        let mut qmp: Option<Qmp> = None;
+        let mut connected = false;
+        let mut keys_sent = config.send_keys.is_empty();
+        while running(&child) {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                if let Some(qmp) = &mut qmp {
+                    let notes = monitor.run_hooks(qmp, &config.on_timeout);
+                    state.notes.lock().unwrap().extend(notes);
+                }
+                state.timed_out.store(true, Ordering::SeqCst);
+                child.lock().unwrap().kill().ok();
+                return;
+            }
+            let Some(connection) = &mut qmp else {
+                // QEMU only accepts one QMP client, so a lost connection is not retried.
+                if !connected {
+                    qmp = Qmp::connect(&monitor.socket).ok();
+                    connected = qmp.is_some();
+                }
+                thread::sleep(Duration::from_millis(50));
+                continue;
+            };
+            if !keys_sent && started.elapsed() >= Duration::from_secs(config.keys_delay) {
+                for keys in &config.send_keys {
+                    if let Err(e) = connection.send_key(keys) {
+                        let note = format!("Cannot send key {} {}", keys, e);
+                        state.notes.lock().unwrap().push(note);
+                    }
+                }
+                keys_sent = true;
+            }
+            match connection.next_event() {
+                Ok(Some(event)) if event["event"] == "GUEST_PANICKED" => {
+                    let notes = monitor.run_hooks(connection, &config.on_panic);
+                    state.notes.lock().unwrap().extend(notes);
+                    state.panicked.store(true, Ordering::SeqCst);
+                    if connection.quit().is_err() {
+                        child.lock().unwrap().kill().ok();
+                    }
+                    return;
+                }
+                Ok(Some(event)) => note_reset(&event, &state),
+                Ok(None) => {}
+                Err(_) => qmp = None,
+            }
+        }
+        // QEMU exits right after a `-no-reboot` reset, before the loop sees the event.
+        if let Some(connection) = &mut qmp {
+            while let Ok(Some(event)) = connection.next_event() {
+                note_reset(&event, &state);
+            }
+        }
+    })
+}
+
+fn note_reset(event: &serde_json::Value, state: &RunState) {
+    let reason = &event["data"]["reason"];
+    if ["SHUTDOWN", "RESET"].contains(&event["event"].as_str().unwrap_or_default())
+        && reason == "guest-reset"
+    {
+        state.reset.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Whether QEMU logged a triple fault to `log` (`-d cpu_reset`, TCG only), and
+/// the last interrupts it logged before it (`-d int`).
+pub fn triple_fault_log(log: &Path) -> (bool, Vec<String>) {
+    // `-d int` logs every timer tick, so only the end of the log is read.
+    let mut tail = Vec::new();
+    if let Ok(mut file) = fs::File::open(log) {
+        let length = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        file.seek(SeekFrom::Start(length.saturating_sub(64 * 1024)))
+            .ok();
+        file.read_to_end(&mut tail).ok();
+    }
+    let tail = String::from_utf8_lossy(&tail);
+    let lines: Vec<&str> = tail.lines().collect();
+    let end = match lines.iter().rposition(|line| line.contains("Triple fault")) {
+        Some(end) => end,
+        None => return (false, Vec::new()),
+    };
+    // Interrupt entries start like `     5: v=0e e=0002 i=0 cpl=0 IP=0008:...`.
+    let starts: Vec<usize> = lines[..end]
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| line.trim_start().split_once(": v=").is_some())
+        .map(|(index, _)| index)
+        .collect();
+    let start = starts
+        .len()
+        .checked_sub(3)
+        .map(|index| starts[index])
+        .unwrap_or(end);
+    let frames = lines[start..=end]
+        .iter()
+        .map(|line| line.to_string())
+        .collect();
+    (true, frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_memory_sizes_as_megabytes() {
+        assert_eq!(megabytes("512"), Ok(512));
+        assert_eq!(megabytes("256M"), Ok(256));
+        assert_eq!(megabytes("2G"), Ok(2048));
+        assert!(megabytes("lots").is_err());
+    }
+
+    #[test]
+    fn bochsrc_takes_memory_and_smp_from_the_machine() {
+        let machine = MachineConfig {
+            memory: Some(String::from("1G")),
+            smp: Some(2),
+            ..Default::default()
+        };
+        let bochsrc = bochsrc_for(Path::new("os.iso"), Path::new("bochsrc.txt"), &machine);
+        let bochsrc = bochsrc.unwrap();
+        assert!(bochsrc.starts_with("megs: 1024\ncpu: count=2\n"));
+
+        let bochsrc = bochsrc_for(
+            Path::new("os.iso"),
+            Path::new("bochsrc.txt"),
+            &MachineConfig::default(),
+        );
+        assert!(bochsrc.unwrap().starts_with("megs: 128\nromimage:"));
+    }
+}
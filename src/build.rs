@@ -0,0 +1,702 @@
+use std::ffi::OsStr;
+use std::fs::{create_dir, remove_dir_all};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use std::{env, fmt, fs};
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use crate::depinfo::{CrateType, DepInfo};
+use crate::disk;
+use crate::machine::Drive;
+use crate::profile;
+use crate::project::Project;
+
+/// A kernel linked and packed into a bootable ISO by an [`ImageBuilder`].
+pub struct KernelBuild {
+    pub iso: PathBuf,
+    /// The kernel package, which the emulator runs in.
+    pub work_dir: PathBuf,
+    /// Unstripped kernel ELF for GDB and symbolizing, in `target/osc/<profile>` or
+    /// the stage directory.
+    pub symbols: PathBuf,
+    /// Data disk images built next to `symbols`, copied fresh for every boot.
+    pub disks: Vec<Drive>,
+    progress_bar: ProgressBar,
+}
+
+/// Why a kernel image could not be built.
+#[derive(Debug)]
+pub enum BuildError {
+    /// `cargo build` failed; cargo has printed why.
+    Cargo,
+    /// The kernel's executable is not where cargo or the target says it is.
+    Artifact(PathBuf),
+    /// A tool osc runs could not be started or failed.
+    Tool(&'static str, String),
+    /// No combination of the objects cargo built linked into a kernel.
+    Link(PathBuf),
+    Io(PathBuf, io::Error),
+    Disk(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::Cargo => write!(f, "cargo failed to build the kernel"),
+            BuildError::Artifact(path) => {
+                write!(
+                    f,
+                    "cannot find the kernel cargo built at {}",
+                    path.display()
+                )
+            }
+            BuildError::Tool(tool, reason) => write!(f, "{}: {}", tool, reason),
+            BuildError::Link(path) => write!(
+                f,
+                "cannot link a kernel from {} with the objects in deps/",
+                path.display()
+            ),
+            BuildError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            BuildError::Disk(e) => write!(f, "cannot build data disk: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Runs `tool` and fails unless it exits successfully.
+fn run_tool(tool: &'static str, command: &mut Command) -> Result<(), BuildError> {
+    let status = command
+        .status()
+        .map_err(|e| BuildError::Tool(tool, format!("cannot run it: {}", e)))?;
+    if !status.success() {
+        return Err(BuildError::Tool(tool, format!("failed with {}", status)));
+    }
+    Ok(())
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> BuildError + '_ {
+    move |e| BuildError::Io(path.to_path_buf(), e)
+}
+
+impl KernelBuild {
+    /// Stops the link progress bar, leaving it on the terminal.
+    pub fn finish_progress(&self) {
+        self.progress_bar.finish();
+    }
+
+    /// Runs `cargo build` with `cargo_args` and links the kernel it built into an ISO.
+    pub fn cargo(
+        project: &Project,
+        current_dir: &Path,
+        cargo_args: &[String],
+    ) -> Result<Self, BuildError> {
+        let mut cargo = Command::new("cargo");
+        cargo
+            .arg("build")
+            .arg("--message-format=json-render-diagnostics")
+            .args(cargo_args)
+            .current_dir(current_dir);
+        let messages = cargo_messages(cargo)?;
+        // Cargo knows where it put the kernel, wherever the target dir is. Without
+        // `-p` it builds every workspace member, so host bins are reported too.
+        let reported = messages.iter().find_map(|message| {
+            let bin = message["target"]["kind"]
+                .as_array()
+                .is_some_and(|kinds| kinds.iter().any(|kind| kind == "bin"));
+            let executable = message["executable"].as_str()?;
+            (message["reason"] == "compiler-artifact"
+                && message["profile"]["test"] != true
+                && bin
+                && message["target"]["name"] == project.crate_name.as_str()
+                && is_kernel_package(project, message))
+            .then(|| PathBuf::from(executable))
+        });
+        let kernel = match reported {
+            Some(kernel) => kernel,
+            None => {
+                let profile = profile::from_args(cargo_args, "dev");
+                project
+                    .target_dir()
+                    .ok_or_else(|| BuildError::Artifact(PathBuf::from(&project.crate_name)))?
+                    .join(profile::dir_name(&profile))
+                    .join(&project.crate_name)
+            }
+        };
+        ImageBuilder::new(project, kernel)
+            .current_dir(current_dir)
+            .libs(artifact_libs(project, &messages))
+            .build()
+    }
+}
+
+/// Links a kernel binary cargo built and packs it into an ISO, the step
+/// `osc runner` does before booting.
+pub struct ImageBuilder<'a> {
+    project: &'a Project,
+    artifact: PathBuf,
+    current_dir: PathBuf,
+    stage: Option<PathBuf>,
+    libs: Option<Vec<PathBuf>>,
+}
+
+impl<'a> ImageBuilder<'a> {
+    /// `artifact` is the executable cargo built; a relative path is taken from
+    /// the current directory.
+    pub fn new(project: &'a Project, artifact: impl Into<PathBuf>) -> Self {
+        ImageBuilder {
+            project,
+            artifact: artifact.into(),
+            current_dir: env::current_dir().unwrap_or_default(),
+            stage: None,
+            libs: None,
+        }
+    }
+
+    /// The directory relative artifact paths are resolved against, the one cargo
+    /// ran the runner in.
+    pub fn current_dir(mut self, current_dir: impl Into<PathBuf>) -> Self {
+        self.current_dir = current_dir.into();
+        self
+    }
+
+    /// Writes everything into `stage` instead of next to cargo's output and the
+    /// project, so several images can be built at once.
+    pub fn stage(mut self, stage: impl Into<PathBuf>) -> Self {
+        self.stage = Some(stage.into());
+        self
+    }
+
+    /// The lib's static libraries cargo reported building along with the
+    /// artifact. Without them the lib is picked from the dep-info in `deps/`:
+    /// by `test-lib-marker` if set, otherwise the newest one that links.
+    pub fn libs(mut self, libs: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.libs = Some(libs.into_iter().collect());
+        self
+    }
+
+    pub fn build(&self) -> Result<KernelBuild, BuildError> {
+        build_iso(
+            &self.artifact,
+            &self.current_dir,
+            self.project,
+            self.stage.as_deref(),
+            self.libs.as_deref(),
+        )
+    }
+}
+
+/// Links the kernel cargo built at `path` and packs it into an ISO.
+///
+/// With a `stage` directory every file osc writes, including a fresh copy of the
+/// `iso/` tree, goes there instead of next to cargo's output and the project, so
+/// several kernels can be built at the same time.
+fn build_iso(
+    path: &Path,
+    current_dir: &Path,
+    project: &Project,
+    stage: Option<&Path>,
+    libs: Option<&[PathBuf]>,
+) -> Result<KernelBuild, BuildError> {
+    let work_dir = project.kernel_dir.as_path();
+    let path = current_dir.join(path);
+    let path = path.as_path();
+    let (Some(parent), Some(file_name)) = (path.parent(), path.file_name().and_then(OsStr::to_str))
+    else {
+        return Err(BuildError::Artifact(path.to_path_buf()));
+    };
+    let in_deps = parent.file_name() == Some(OsStr::new("deps"));
+    // The lib's unit-test binary already contains the whole crate, so it is linked
+    // on its own instead of against the crate's static library.
+    let lib_test = in_deps && is_lib_test(path, project);
+
+    let mut prefix = file_name;
+    if !in_deps {
+        prefix = get_first_segment(file_name);
+    }
+    let directory = match (in_deps, parent.parent()) {
+        (true, Some(directory)) => directory,
+        (true, None) => return Err(BuildError::Artifact(path.to_path_buf())),
+        (false, _) => parent,
+    };
+    let deps_dir = directory.join(PathBuf::from("deps"));
+    let scratch = match stage {
+        Some(stage) => {
+            fs::create_dir_all(stage).map_err(io_error(stage))?;
+            stage
+        }
+        None => directory,
+    };
+    let debug = project
+        .cargo_profiles
+        .debuginfo(profile::of_artifact(path).unwrap_or("dev"));
+    let mut index = 0;
+    let mut error_count = 0;
+    let mut count = 0;
+    let mut result: Vec<(Box<Path>, Duration)> = Vec::new();
+    let bin_dir = scratch.join(PathBuf::from("build-temp-bin"));
+    if bin_dir.exists() {
+        remove_dir_all(&bin_dir).map_err(io_error(&bin_dir))?;
+    }
+    create_dir(&bin_dir).map_err(io_error(&bin_dir))?;
+    let temp_dir = scratch.join(PathBuf::from("build-temp"));
+
+    let progress_bar = ProgressBar::new(200);
+    if stage.is_some() {
+        progress_bar.set_draw_target(ProgressDrawTarget::hidden());
+    }
+
+    let style = ProgressStyle::default_bar()
+        .template(
+            "Compiling your os: [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({percent}%)",
+        )
+        .expect("")
+        .progress_chars("#>-");
+    progress_bar.set_style(style);
+
+    while {
+        let mut inc = false;
+        for bin_index in index..=index + 5 {
+            if error_count >= 15 {
+                break;
+            }
+            for lib_index in index..=index + 5 {
+                if error_count >= 15 {
+                    break;
+                }
+                count += 1;
+                if temp_dir.exists() {
+                    remove_dir_all(&temp_dir).map_err(io_error(&temp_dir))?;
+                }
+                create_dir(&temp_dir).map_err(io_error(&temp_dir))?;
+                let created = get_object(
+                    bin_index,
+                    lib_index,
+                    scratch,
+                    path,
+                    deps_dir.clone(),
+                    prefix,
+                    project,
+                    libs,
+                    debug,
+                    lib_test,
+                )?;
+                let object_files: Vec<String> = fs::read_dir(&temp_dir)
+                    .map_err(io_error(&temp_dir))?
+                    .filter_map(|entry| {
+                        if let Ok(entry) = entry {
+                            if let Some(extension) = entry.path().extension() {
+                                if extension == "o" {
+                                    Some(entry.path().to_string_lossy().into_owned())
+                                } else {
+                                    None
+                                }
+                            } else {
+                                None
+                            }
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+                let mut command = std::process::Command::new("ld");
+                command
+                    .arg("-n")
+                    .arg("--gc-sections")
+                    .arg("-o")
+                    .arg(
+                        scratch.join(
+                            PathBuf::from("build-temp-bin")
+                                .join(PathBuf::from(format!("{}.bin", count))),
+                        ),
+                    )
+                    .arg("-T")
+                    .arg(work_dir.join(PathBuf::from("linker.ld")).to_str().unwrap())
+                    .current_dir(scratch.join(PathBuf::from("build-temp")))
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null());
+                for object_file in &object_files {
+                    command.arg(object_file);
+                }
+                let status = command
+                    .status()
+                    .map_err(|e| BuildError::Tool("ld", format!("cannot run it: {}", e)))?;
+                if !status.success()
+                    || !scratch
+                        .join(
+                            PathBuf::from("build-temp-bin")
+                                .join(PathBuf::from(format!("{}.bin", count))),
+                        )
+                        .exists()
+                {
+                    inc = true;
+                    error_count += 1;
+                } else {
+                    if let Some(value) = created {
+                        result.push((
+                            Box::from(
+                                scratch.join(
+                                    PathBuf::from("build-temp-bin")
+                                        .join(PathBuf::from(format!("{}.bin", count))),
+                                ),
+                            ),
+                            value,
+                        ));
+                    }
+                    error_count = 0;
+                }
+                progress_bar.inc(error_count);
+            }
+        }
+        progress_bar.set_length(progress_bar.length().unwrap_or(0).saturating_sub(30));
+        inc
+    } {
+        std::thread::sleep(Duration::from_secs(1));
+        index += 1;
+    }
+    if let Some((min_path, _min_duration)) = result.iter().min_by_key(|&(_, duration)| duration) {
+        let (image_dir, symbols_dir) = match stage {
+            Some(stage) => {
+                let iso_dir = work_dir.join("iso");
+                copy_dir(&iso_dir, &stage.join("iso")).map_err(io_error(&iso_dir))?;
+                (stage, stage.to_path_buf())
+            }
+            None => (
+                work_dir,
+                project
+                    .target_root
+                    .join("osc")
+                    .join(directory.file_name().unwrap()),
+            ),
+        };
+        let symbols = install_kernel(min_path, image_dir, &symbols_dir, project.osc_config.strip)?;
+        let disks = disk::build_images(&project.osc_config.disks, work_dir, &symbols_dir)
+            .map_err(|e| BuildError::Disk(e.to_string()))?;
+        let mut iso_grub = Command::new("grub-mkrescue");
+        iso_grub
+            .arg("-o")
+            .arg("os.iso")
+            .arg("iso")
+            .current_dir(image_dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        run_tool("grub-mkrescue", &mut iso_grub)?;
+        return Ok(KernelBuild {
+            iso: image_dir.join(PathBuf::from("os.iso")),
+            work_dir: work_dir.to_path_buf(),
+            symbols,
+            disks,
+            progress_bar,
+        });
+    }
+    Err(BuildError::Link(path.to_path_buf()))
+}
+
+/// Keeps the full-symbol ELF as `kernel.sym` in `symbols_dir` (normally
+/// `target/osc/<profile>`) and copies the kernel onto the ISO tree, stripped
+/// unless `strip = false`.
+fn install_kernel(
+    linked: &Path,
+    work_dir: &Path,
+    symbols_dir: &Path,
+    strip: bool,
+) -> Result<PathBuf, BuildError> {
+    fs::create_dir_all(symbols_dir).map_err(io_error(symbols_dir))?;
+    let symbols = symbols_dir.join("kernel.sym");
+    fs::copy(linked, &symbols).map_err(io_error(&symbols))?;
+
+    let kernel = work_dir
+        .join(PathBuf::from("iso").join(PathBuf::from("boot").join(PathBuf::from("kernel.bin"))));
+    // A failed strip must not leave the previous build's kernel to be booted.
+    if kernel.exists() {
+        fs::remove_file(&kernel).map_err(io_error(&kernel))?;
+    }
+    if strip {
+        run_tool(
+            "strip",
+            Command::new("strip").arg("-o").arg(&kernel).arg(&symbols),
+        )?;
+    } else {
+        fs::copy(&symbols, &kernel).map_err(io_error(&kernel))?;
+    }
+    Ok(symbols)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    if to.exists() {
+        remove_dir_all(to)?;
+    }
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()))?;
+        } else {
+            fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+fn get_files_with_extension_and_prefix(
+    directory_path: &Path,
+    extension: &str,
+    prefix: &str,
+) -> Vec<std::path::PathBuf> {
+    let mut result = Vec::new();
+
+    if let Ok(entries) = fs::read_dir(directory_path) {
+        for entry in entries.flatten() {
+            if let Ok(file_type) = entry.file_type() {
+                if file_type.is_file() {
+                    if let Some(file_name) = entry.file_name().to_str() {
+                        if file_name.starts_with(prefix) && file_name.ends_with(extension) {
+                            result.push(entry.path());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+fn get_object(
+    index: usize,
+    lib_index: usize,
+    directory: &Path,
+    path: &Path,
+    deps_dir: PathBuf,
+    prefix: &str,
+    project: &Project,
+    libs: Option<&[PathBuf]>,
+    debug: bool,
+    lib_test: bool,
+) -> Result<Option<Duration>, BuildError> {
+    get_asm(&project.kernel_dir, directory, debug)?;
+    if lib_test {
+        return get_bin(index, deps_dir, prefix, directory, project, lib_test);
+    }
+    let val = get_lib(directory, path, deps_dir.clone(), project, libs, &lib_index)?;
+    get_bin(index, deps_dir, prefix, directory, project, lib_test)?;
+    Ok(val)
+}
+
+/// Whether a test binary in `deps/` is the lib's unit-test harness, going by the
+/// crate root in its dep-info.
+fn is_lib_test(path: &Path, project: &Project) -> bool {
+    DepInfo::read(&PathBuf::from(format!("{}.d", path.display())))
+        .is_ok_and(|dep_info| project.is_lib(&dep_info))
+}
+
+fn get_asm(work_dir: &Path, directory: &Path, debug: bool) -> Result<(), BuildError> {
+    let boot_dir = work_dir.join(PathBuf::from("src").join(PathBuf::from("boot")));
+    let asm_files: Vec<String> = fs::read_dir(&boot_dir)
+        .map_err(io_error(&boot_dir))?
+        .filter_map(|entry| {
+            if let Ok(entry) = entry {
+                if let Some(extension) = entry.path().extension() {
+                    if extension == "asm" {
+                        Some(entry.path().to_string_lossy().into_owned())
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
+        })
+        .collect();
+    for asm_file in &asm_files {
+        let mut command = std::process::Command::new("nasm");
+        command.arg("-felf64").arg(asm_file).arg("-o").arg(
+            directory.join(
+                PathBuf::from("build-temp").join(PathBuf::from(
+                    String::from(
+                        PathBuf::from(asm_file)
+                            .file_name()
+                            .unwrap()
+                            .to_str()
+                            .unwrap(),
+                    )
+                    .replace("asm", "o"),
+                )),
+            ),
+        );
+        if debug {
+            command.arg("-g");
+        }
+        run_tool("nasm", &mut command)?;
+    }
+    Ok(())
+}
+
+fn get_lib(
+    directory: &Path,
+    path: &Path,
+    deps_dir: PathBuf,
+    project: &Project,
+    libs: Option<&[PathBuf]>,
+    index: &usize,
+) -> Result<Option<Duration>, BuildError> {
+    let files = match libs {
+        Some(libs) => libs.to_vec(),
+        None => lib_candidates(path, &deps_dir, project),
+    };
+    if let Some(file) = files.get(*index) {
+        let temp_dir = directory.join(PathBuf::from("build-temp"));
+        let copy = temp_dir.join(file.file_name().unwrap_or_default());
+        fs::copy(file, &copy).map_err(io_error(file))?;
+        extract_static_library(&copy, &temp_dir)?;
+        return Ok(fs::metadata(file)
+            .and_then(|metadata| metadata.created())
+            .ok()
+            .and_then(|created| created.elapsed().ok()));
+    }
+    Ok(None)
+}
+
+/// The lib's static libraries in `deps_dir` that may belong to the build of
+/// `path`, found by the crate root in every dep-info file since rustc names them
+/// after the crate rather than the package. Test binaries live in `deps/` and are
+/// linked against the lib built for tests, which `test-lib-marker` picks out when
+/// set.
+fn lib_candidates(path: &Path, deps_dir: &Path, project: &Project) -> Vec<PathBuf> {
+    let test = path.parent().unwrap().file_name().unwrap() == "deps";
+    let marker = project
+        .osc_config
+        .test_lib_marker
+        .as_ref()
+        .map(|marker| project.kernel_dir.join(marker));
+    let mut files: Vec<PathBuf> = Vec::new();
+    for file in get_files_with_extension_and_prefix(deps_dir, ".d", "") {
+        let dep_info = match DepInfo::read(&file) {
+            Ok(dep_info) => dep_info,
+            Err(_) => continue,
+        };
+        if !project.is_lib(&dep_info) {
+            continue;
+        }
+        if let Some(marker) = &marker {
+            let test_lib = dep_info
+                .sources()
+                .any(|source| project.source_path(source) == *marker);
+            if test_lib != test {
+                continue;
+            }
+        }
+        files.extend(
+            dep_info
+                .outputs_of_type(CrateType::StaticLib)
+                .map(Path::to_path_buf),
+        );
+    }
+    files
+}
+
+/// Runs `cargo` with `--message-format=json-*` and collects the JSON messages it
+/// prints.
+pub fn cargo_messages(mut cargo: Command) -> Result<Vec<serde_json::Value>, BuildError> {
+    let cannot_run = |e: io::Error| BuildError::Tool("cargo", format!("cannot run it: {}", e));
+    let mut child = cargo.stdout(Stdio::piped()).spawn().map_err(cannot_run)?;
+    let messages = BufReader::new(child.stdout.take().unwrap())
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect();
+    if !child.wait().map_err(cannot_run)?.success() {
+        return Err(BuildError::Cargo);
+    }
+    Ok(messages)
+}
+
+/// Whether a cargo JSON message is about the kernel package rather than another
+/// workspace member.
+pub fn is_kernel_package(project: &Project, message: &serde_json::Value) -> bool {
+    message["manifest_path"]
+        .as_str()
+        .is_some_and(|manifest| Path::new(manifest) == project.kernel_dir.join("Cargo.toml"))
+}
+
+/// Static libraries of the package's lib among cargo's `compiler-artifact`
+/// messages, for [`ImageBuilder::libs`].
+pub fn artifact_libs(project: &Project, messages: &[serde_json::Value]) -> Vec<PathBuf> {
+    messages
+        .iter()
+        .filter(|message| message["reason"] == "compiler-artifact")
+        .filter(|message| {
+            message["target"]["src_path"]
+                .as_str()
+                .is_some_and(|src_path| Path::new(src_path) == project.lib_path)
+        })
+        .flat_map(|message| message["filenames"].as_array().into_iter().flatten())
+        .filter_map(serde_json::Value::as_str)
+        .map(PathBuf::from)
+        .filter(|file| CrateType::of(file) == CrateType::StaticLib)
+        .collect()
+}
+
+fn get_bin(
+    index: usize,
+    deps_dir: PathBuf,
+    prefix: &str,
+    directory: &Path,
+    project: &Project,
+    lib_test: bool,
+) -> Result<Option<Duration>, BuildError> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    for file in get_files_with_extension_and_prefix(&deps_dir, ".d", prefix) {
+        let dep_info = match DepInfo::read(&file) {
+            Ok(dep_info) => dep_info,
+            Err(_) => continue,
+        };
+        if lib_test || !project.is_lib(&dep_info) {
+            files.extend(
+                dep_info
+                    .outputs_of_type(CrateType::Object)
+                    .map(Path::to_path_buf),
+            );
+        }
+    }
+    if let Some(file) = files.get(index) {
+        let copy = directory
+            .join(PathBuf::from("build-temp"))
+            .join(file.file_name().unwrap_or_default());
+        fs::copy(file, copy).map_err(io_error(file))?;
+        return Ok(fs::metadata(file)
+            .and_then(|metadata| metadata.created())
+            .ok()
+            .and_then(|created| created.elapsed().ok()));
+    }
+    Ok(None)
+}
+
+fn extract_static_library(library_path: &Path, output_directory: &Path) -> Result<(), BuildError> {
+    // Create the output directory if it doesn't exist
+    fs::create_dir_all(output_directory).map_err(io_error(output_directory))?;
+
+    // Use the ar command to extract the .a file into the output directory
+    run_tool(
+        "ar",
+        Command::new("ar")
+            .arg("x")
+            .arg(library_path)
+            .current_dir(output_directory),
+    )
+}
+
+/// The part of a cargo artifact name before the `-<hash>` suffix.
+pub fn get_first_segment(input: &str) -> &str {
+    if let Some(index) = input.rfind('-') {
+        &input[0..index]
+    } else {
+        input
+    }
+}
@@ -0,0 +1,64 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use toml::Value;
+
+/// Cargo's configuration as cargo itself reads it: `.cargo/config.toml` (or the
+/// legacy `.cargo/config`) in the directory cargo runs in and every parent, then
+/// the one in `$CARGO_HOME`. Files nearer the current directory win.
+#[derive(Debug, Clone, Default)]
+pub struct CargoConfig {
+    /// Each file's contents with the directory holding its `.cargo/`, nearest first.
+    layers: Vec<(PathBuf, Value)>,
+}
+
+impl CargoConfig {
+    pub fn discover(current_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut paths: Vec<PathBuf> = current_dir
+            .ancestors()
+            .filter_map(|dir| config_file(&dir.join(".cargo")))
+            .collect();
+        if let Some(home_config) = cargo_home().and_then(|home| config_file(&home)) {
+            if !paths.contains(&home_config) {
+                paths.push(home_config);
+            }
+        }
+        let mut layers = Vec::new();
+        for path in paths {
+            let text =
+                fs::read_to_string(&path).map_err(|e| format!("{} {}", path.display(), e))?;
+            let value: Value =
+                toml::de::from_str(&text).map_err(|e| format!("{} {}", path.display(), e))?;
+            // Cargo resolves relative config paths against the directory holding `.cargo/`.
+            let root = path.parent().unwrap().parent().unwrap().to_path_buf();
+            layers.push((root, value));
+        }
+        Ok(CargoConfig { layers })
+    }
+
+    /// The value at `keys` from the nearest file that sets it, with the directory
+    /// relative paths in that file are taken from.
+    pub fn get(&self, keys: &[&str]) -> Option<(&Value, &Path)> {
+        self.layers.iter().find_map(|(root, value)| {
+            let value = keys.iter().try_fold(value, |value, key| value.get(key))?;
+            Some((value, root.as_path()))
+        })
+    }
+}
+
+/// `config.toml` in `dir`, or `config` which cargo still reads and prefers when
+/// both exist.
+fn config_file(dir: &Path) -> Option<PathBuf> {
+    ["config", "config.toml"]
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+fn cargo_home() -> Option<PathBuf> {
+    env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cargo")))
+}
@@ -0,0 +1,62 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::profile;
+
+/// Removes what `osc build` and `osc runner` leave behind: the link scratch
+/// directories next to cargo's output, `target/osc` and, when every profile is
+/// cleaned, the kernel copied into the ISO tree and `os.iso` itself.
+///
+/// Everything else in `iso/` and cargo's own artifacts are left alone.
+pub fn clean(
+    kernel_dir: &Path,
+    target_root: &Path,
+    target_dir: Option<&Path>,
+    profile: Option<&str>,
+) {
+    let profile = profile.map(profile::dir_name);
+    let mut profile_dirs: Vec<PathBuf> = Vec::new();
+    if let Some(target_dir) = target_dir {
+        match profile {
+            Some(profile) => profile_dirs.push(target_dir.join(profile)),
+            None => {
+                if let Ok(entries) = fs::read_dir(target_dir) {
+                    for entry in entries.flatten() {
+                        if entry.path().is_dir() {
+                            profile_dirs.push(entry.path());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for profile_dir in profile_dirs {
+        remove(&profile_dir.join("build-temp"));
+        remove(&profile_dir.join("build-temp-bin"));
+    }
+
+    let osc_dir = target_root.join("osc");
+    match profile {
+        Some(profile) => remove(&osc_dir.join(profile)),
+        None => {
+            remove(&osc_dir);
+            remove(&kernel_dir.join(PathBuf::from("iso/boot/kernel.bin")));
+            remove(&kernel_dir.join(PathBuf::from("os.iso")));
+        }
+    }
+}
+
+fn remove(path: &Path) {
+    let result = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else if path.exists() {
+        fs::remove_file(path)
+    } else {
+        return;
+    };
+    match result {
+        Ok(()) => println!("Removed {}", path.display()),
+        Err(e) => eprintln!("Cannot remove {} {}", path.display(), e),
+    }
+}
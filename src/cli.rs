@@ -0,0 +1,264 @@
+use std::error::Error;
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::{env, process};
+
+use crate::build::{get_first_segment, ImageBuilder, KernelBuild};
+use crate::project::Project;
+use crate::runner::Runner;
+use crate::{clean, config, doctor, init, test};
+
+fn error_c(err: Option<Box<dyn Error>>) {
+    if let Some(error) = err {
+        eprintln!(
+            "Error cannot find right configuration for this directory {}",
+            error
+        );
+    } else {
+        eprintln!("Error cannot find right configuration for this directory");
+    }
+}
+
+/// The `osc` command line; `src/main.rs` only calls this.
+pub fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    // `osc [--config key=value]... <mode>`: settings come before the mode, so that
+    // cargo's own `--config` after it still reaches cargo.
+    // `--config` wins over `OSC_*` variables by coming after them.
+    let mut settings = match config::env_settings(env::vars()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    while args.len() > 1 {
+        let setting = match args[1].strip_prefix("--config=") {
+            Some(setting) => String::from(setting),
+            None if args[1] == "--config" && args.len() > 2 => args.remove(2),
+            None => break,
+        };
+        args.remove(1);
+        match config::parse_setting(&setting) {
+            Ok(setting) => settings.push(setting),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(mode) = args.get(1) {
+        let current_dir = match env::current_dir() {
+            Ok(current_dir) => current_dir,
+            Err(e) => {
+                error_c(Some(Box::from(e)));
+                process::exit(1);
+            }
+        };
+        // There is no project to discover before `init` has set it up.
+        if mode == "init" {
+            if let Err(e) = init::init(&current_dir, &args[2..]) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+            return;
+        }
+        let package = args
+            .iter()
+            .skip(2)
+            .position(|arg| arg == "-p" || arg == "--package")
+            .and_then(|index| args.get(index + 3))
+            .map(String::as_str);
+        if mode == "doctor" {
+            if !doctor::doctor(&current_dir, package, &settings) {
+                process::exit(1);
+            }
+            return;
+        }
+        let project = match Project::discover_with_settings(&current_dir, package, &settings) {
+            Ok(project) => project,
+            Err(e) => {
+                error_c(Some(e));
+                process::exit(1);
+            }
+        };
+        if mode == "build" {
+            let project = project.for_cargo_args(&args[2..], &current_dir, "dev");
+            match KernelBuild::cargo(&project, &current_dir, &args[2..]) {
+                Ok(build) => {
+                    build.finish_progress();
+                    println!("Kernel symbols: {}", build.symbols.display());
+                }
+                Err(e) => {
+                    eprintln!("Cannot build a kernel image: {}", e);
+                    process::exit(1);
+                }
+            }
+        } else if mode == "run" {
+            let (build_args, run_args) = match args[2..].iter().position(|arg| arg == "--") {
+                Some(index) => (&args[2..index + 2], args[index + 3..].to_vec()),
+                None => (&args[2..], Vec::new()),
+            };
+            let mut cargo_args: Vec<String> = Vec::new();
+            let mut run_profile: Option<String> = None;
+            let mut build_args = build_args.iter();
+            while let Some(arg) = build_args.next() {
+                match arg.as_str() {
+                    "--run-profile" => run_profile = build_args.next().cloned(),
+                    _ => cargo_args.push(arg.clone()),
+                }
+            }
+            let project = project.for_cargo_args(&cargo_args, &current_dir, "dev");
+            let osc_config = &project.osc_config;
+            let machine = match osc_config.machine(run_profile.as_deref()) {
+                Ok(machine) => machine,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            };
+            let build = match KernelBuild::cargo(&project, &current_dir, &cargo_args) {
+                Ok(build) => build,
+                Err(e) => {
+                    eprintln!("Cannot build a kernel image: {}", e);
+                    process::exit(1);
+                }
+            };
+            build.finish_progress();
+            let runner = Runner::new(&project, &build)
+                .args(run_args)
+                .machine(machine);
+            match runner.run(&mut io::stdout()) {
+                Ok(code) => process::exit(code.unwrap_or(1)),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            }
+        } else if mode == "runner" {
+            let mut runner_args = &args[2..];
+            let mut run_profile = None;
+            if runner_args.first().map(String::as_str) == Some("--run-profile") {
+                run_profile = runner_args.get(1);
+                runner_args = runner_args.get(2..).unwrap_or_default();
+            }
+            let Some(path) = runner_args.first() else {
+                eprintln!("osc runner needs the path of the kernel to boot, which cargo passes it");
+                process::exit(1);
+            };
+            let project = project.for_artifact(&current_dir.join(path));
+            let osc_config = &project.osc_config;
+            let machine = match osc_config.machine(run_profile.map(String::as_str)) {
+                Ok(machine) => machine,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            };
+            // Cargo keeps test binaries in `deps/` and copies bins out of it.
+            let test =
+                Path::new(path).parent().and_then(Path::file_name) == Some(OsStr::new("deps"));
+            let Some(file_name) = Path::new(path).file_name().and_then(OsStr::to_str) else {
+                eprintln!(
+                    "Cannot build a kernel image from {}: it is not a file",
+                    path
+                );
+                process::exit(1);
+            };
+            let test_name = get_first_segment(file_name);
+            if test && osc_config.skip.iter().any(|skip| skip == test_name) {
+                println!("Skipping {}", test_name);
+                return;
+            }
+            let builder = ImageBuilder::new(&project, path).current_dir(&current_dir);
+            let build = match builder.build() {
+                Ok(build) => build,
+                Err(e) => {
+                    eprintln!("Cannot build a kernel image from {}: {}", path, e);
+                    process::exit(1);
+                }
+            };
+            build.finish_progress();
+            let code = match Runner::new(&project, &build)
+                .args(runner_args[1..].to_vec())
+                .machine(machine)
+                .test(test)
+                .run(&mut io::stdout())
+            {
+                Ok(code) => code,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            };
+            if test {
+                if code == osc_config.test_success_exit_code.or(Some(0)) {
+                    println!("Test {} passed", test_name);
+                    process::exit(0);
+                }
+                match code {
+                    Some(code) => {
+                        eprintln!("Test {} failed with exit code {}", test_name, code)
+                    }
+                    None => eprintln!("Test {} was killed by a signal", test_name),
+                }
+                process::exit(1);
+            }
+            process::exit(code.unwrap_or(1));
+        } else if mode == "test" {
+            let mut jobs: Option<usize> = None;
+            let mut profiles: Vec<String> = Vec::new();
+            let mut cargo_args: Vec<String> = Vec::new();
+            let mut test_args = args.iter().skip(2);
+            while let Some(arg) = test_args.next() {
+                match arg.as_str() {
+                    "--test-jobs" => jobs = test_args.next().and_then(|jobs| jobs.parse().ok()),
+                    "--run-profile" => {
+                        if let Some(names) = test_args.next() {
+                            profiles.extend(names.split(',').map(String::from));
+                        }
+                    }
+                    _ => cargo_args.push(arg.clone()),
+                }
+            }
+            let project = project.for_cargo_args(&cargo_args, &current_dir, "test");
+            if !test::run_tests(&current_dir, &project, &cargo_args, jobs, &profiles) {
+                process::exit(1);
+            }
+        } else if mode == "clean" {
+            let mut profile: Option<String> = None;
+            let mut cargo_clean = false;
+            let mut clean_args = args.iter().skip(2);
+            while let Some(arg) = clean_args.next() {
+                match arg.as_str() {
+                    "--release" => profile = Some(String::from("release")),
+                    "--profile" => profile = clean_args.next().cloned(),
+                    "--cargo" => cargo_clean = true,
+                    "-p" | "--package" => {
+                        clean_args.next();
+                    }
+                    _ => eprintln!("Unknown clean option {}", arg),
+                }
+            }
+            clean::clean(
+                &project.kernel_dir,
+                &project.target_root,
+                project.target_dir().as_deref(),
+                profile.as_deref(),
+            );
+            if cargo_clean {
+                let mut cargo = Command::new("cargo");
+                cargo.arg("clean").current_dir(&current_dir);
+                if let Some(package) = package {
+                    cargo.arg("-p").arg(package);
+                }
+                if let Some(profile) = &profile {
+                    cargo.arg("--profile").arg(profile);
+                }
+                cargo.status().expect("Cargo clean failed");
+            }
+        }
+    }
+}
@@ -0,0 +1,608 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use toml::Value;
+
+use crate::disk::DataDisk;
+use crate::machine::MachineConfig;
+use crate::qmp::QmpConfig;
+
+/// Settings read from `[package.metadata.osc]` in the kernel's Cargo.toml.
+#[derive(Debug, Clone)]
+pub struct OscConfig {
+    /// QEMU arguments for `osc run` and `osc test`.
+    pub run_args: Vec<String>,
+    pub test_args: Vec<String>,
+    /// Extra bochsrc lines for the Bochs backend.
+    pub bochs_args: Vec<String>,
+    /// Extra arguments for the Cloud Hypervisor backend.
+    pub cloud_hypervisor_args: Vec<String>,
+    /// Put a stripped kernel on the ISO and keep the symbols in `kernel.sym` only.
+    pub strip: bool,
+    /// Resolve code addresses in the kernel's serial output against `kernel.sym`.
+    pub symbolize: bool,
+    /// Exit code the test kernel reports success with, e.g. through `isa-debug-exit`.
+    pub test_success_exit_code: Option<i32>,
+    /// Test binaries that are not booted, by target name (the lib's unit tests use
+    /// the crate name).
+    pub skip: Vec<String>,
+    /// A source file, relative to the package, that only the lib built for tests
+    /// compiles. It tells `osc runner` which of the lib's static libraries in
+    /// `deps/` to link when cargo has not said which one it built.
+    pub test_lib_marker: Option<PathBuf>,
+    /// Emulator binary to run instead of the QEMU picked from the target's arch.
+    pub emulator: Option<String>,
+    /// `qemu`, `bochs` or `cloud-hypervisor`.
+    pub runner_backend: String,
+    /// Seconds a test kernel may run before the emulator is killed.
+    pub test_timeout: Option<u64>,
+    /// Boot QEMU test kernels with `-no-reboot` and interrupt logging, and fail
+    /// them with the logged interrupts when they triple fault.
+    pub detect_triple_faults: bool,
+    /// How many test kernels `osc test` boots at once; defaults to the CPU count.
+    pub test_jobs: Option<usize>,
+    /// `auto` uses KVM when the host allows it, `kvm` and `tcg` force one.
+    pub accel: String,
+    /// CPU model for QEMU when running under TCG.
+    pub tcg_cpu: Option<String>,
+    /// Machine settings every run starts from.
+    pub machine: MachineConfig,
+    /// Extra disks attached to every QEMU run, from `[[package.metadata.osc.disks]]`.
+    pub disks: Vec<DataDisk>,
+    /// QMP hooks from `[package.metadata.osc.qmp]`; QEMU gets a QMP socket when set.
+    pub qmp: Option<QmpConfig>,
+    /// Named machine configurations from `[package.metadata.osc.profiles.<name>]`.
+    pub profiles: BTreeMap<String, RunProfile>,
+    /// Settings for kernels built with a cargo profile, from
+    /// `[package.metadata.osc.cargo-profiles.<name>]` laid over the rest.
+    pub cargo_profiles: BTreeMap<String, OscConfig>,
+}
+
+/// A named machine configuration, picked with `--run-profile`.
+#[derive(Debug, Clone, Default)]
+pub struct RunProfile {
+    /// Profile whose settings this one starts from.
+    pub inherits: Option<String>,
+    pub machine: MachineConfig,
+}
+
+impl Default for OscConfig {
+    fn default() -> Self {
+        Self {
+            run_args: Vec::new(),
+            test_args: Vec::new(),
+            bochs_args: Vec::new(),
+            cloud_hypervisor_args: Vec::new(),
+            strip: true,
+            symbolize: true,
+            test_success_exit_code: None,
+            skip: Vec::new(),
+            test_lib_marker: None,
+            emulator: None,
+            runner_backend: String::from("qemu"),
+            test_timeout: None,
+            detect_triple_faults: true,
+            test_jobs: None,
+            accel: String::from("auto"),
+            tcg_cpu: None,
+            machine: MachineConfig::default(),
+            disks: Vec::new(),
+            qmp: None,
+            profiles: BTreeMap::new(),
+            cargo_profiles: BTreeMap::new(),
+        }
+    }
+}
+
+impl OscConfig {
+    pub fn from_metadata(osc: &Value) -> Result<Self, String> {
+        let mut config = Self::default();
+        if let Some(test_args) = osc.get("test-args").and_then(string_array) {
+            config.test_args = test_args;
+        }
+        if let Some(run_args) = osc.get("run-args").and_then(string_array) {
+            config.run_args = run_args;
+        }
+        if let Some(bochs_args) = osc.get("bochs-args").and_then(string_array) {
+            config.bochs_args = bochs_args;
+        }
+        if let Some(args) = osc.get("cloud-hypervisor-args").and_then(string_array) {
+            config.cloud_hypervisor_args = args;
+        }
+        if let Some(strip) = osc.get("strip").and_then(Value::as_bool) {
+            config.strip = strip;
+        }
+        if let Some(symbolize) = osc.get("symbolize").and_then(Value::as_bool) {
+            config.symbolize = symbolize;
+        }
+        if let Some(code) = osc
+            .get("test-success-exit-code")
+            .and_then(Value::as_integer)
+        {
+            let code = i32::try_from(code)
+                .map_err(|_| format!("`test-success-exit-code = {}` is not an exit code", code))?;
+            config.test_success_exit_code = Some(code);
+        }
+        if let Some(skip) = osc.get("skip").and_then(string_array) {
+            config.skip = skip;
+        }
+        if let Some(marker) = osc.get("test-lib-marker").and_then(Value::as_str) {
+            config.test_lib_marker = Some(PathBuf::from(marker));
+        }
+        if let Some(emulator) = osc.get("emulator").and_then(Value::as_str) {
+            config.emulator = Some(String::from(emulator));
+        }
+        if let Some(backend) = osc.get("runner-backend").and_then(Value::as_str) {
+            config.runner_backend = String::from(backend);
+        }
+        if let Some(timeout) = osc.get("test-timeout").and_then(Value::as_integer) {
+            if timeout <= 0 {
+                return Err(format!(
+                    "`test-timeout = {}` must be a positive number of seconds",
+                    timeout
+                ));
+            }
+            config.test_timeout = Some(timeout as u64);
+        }
+        if let Some(detect) = osc.get("detect-triple-faults").and_then(Value::as_bool) {
+            config.detect_triple_faults = detect;
+        }
+        if let Some(jobs) = osc.get("test-jobs").and_then(Value::as_integer) {
+            config.test_jobs = Some(jobs.max(1) as usize);
+        }
+        if let Some(accel) = osc.get("accel").and_then(Value::as_str) {
+            if !["auto", "kvm", "tcg"].contains(&accel) {
+                return Err(format!(
+                    "`accel = \"{}\"` is not one of auto, kvm or tcg",
+                    accel
+                ));
+            }
+            config.accel = String::from(accel);
+        }
+        if let Some(cpu) = osc.get("tcg-cpu").and_then(Value::as_str) {
+            config.tcg_cpu = Some(String::from(cpu));
+        }
+        config.machine = MachineConfig::from_metadata(osc)?;
+        for disk in osc
+            .get("disks")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            config.disks.push(DataDisk::from_metadata(disk)?);
+        }
+        if let Some(qmp) = osc.get("qmp") {
+            config.qmp = Some(QmpConfig::from_metadata(qmp)?);
+        }
+        if let Some(profiles) = osc.get("profiles").and_then(Value::as_table) {
+            for (name, profile) in profiles {
+                let run_profile = RunProfile {
+                    inherits: profile
+                        .get("inherits")
+                        .and_then(Value::as_str)
+                        .map(String::from),
+                    machine: MachineConfig::from_metadata(profile)
+                        .map_err(|e| format!("run profile `{}`: {}", name, e))?,
+                };
+                config.profiles.insert(name.clone(), run_profile);
+            }
+        }
+        if let Some(cargo_profiles) = osc.get("cargo-profiles").and_then(Value::as_table) {
+            let mut base = osc.clone();
+            if let Some(base) = base.as_table_mut() {
+                base.remove("cargo-profiles");
+            }
+            for (name, overrides) in cargo_profiles {
+                let profile_config = Self::from_metadata(&merge_metadata(&base, overrides))
+                    .map_err(|e| format!("cargo profile `{}`: {}", name, e))?;
+                config.cargo_profiles.insert(name.clone(), profile_config);
+            }
+        }
+        Ok(config)
+    }
+
+    /// The profile called `name` with everything it inherits resolved.
+    pub fn profile(&self, name: &str) -> Result<RunProfile, String> {
+        let mut chain = vec![name.to_string()];
+        let mut profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| format!("no run profile named `{}`", name))?
+            .clone();
+        while let Some(parent_name) = profile.inherits.clone() {
+            if chain.contains(&parent_name) {
+                chain.push(parent_name);
+                return Err(format!(
+                    "run profiles inherit in a cycle: {}",
+                    chain.join(" -> ")
+                ));
+            }
+            let parent = self.profiles.get(&parent_name).ok_or_else(|| {
+                format!(
+                    "run profile `{}` inherits unknown profile `{}`",
+                    chain.last().unwrap(),
+                    parent_name
+                )
+            })?;
+            chain.push(parent_name);
+            profile = RunProfile {
+                inherits: parent.inherits.clone(),
+                machine: profile.machine.overlay(&parent.machine),
+            };
+        }
+        Ok(profile)
+    }
+
+    /// The machine to boot: the top-level settings with `profile` laid over them.
+    pub fn machine(&self, profile: Option<&str>) -> Result<MachineConfig, String> {
+        match profile {
+            Some(profile) => Ok(self.profile(profile)?.machine.overlay(&self.machine)),
+            None => Ok(self.machine.clone()),
+        }
+    }
+}
+
+fn string_array(value: &Value) -> Option<Vec<String>> {
+    value.as_array().map(|array| {
+        array
+            .iter()
+            .filter_map(|value| value.as_str().map(String::from))
+            .collect()
+    })
+}
+
+/// `[workspace.metadata.osc]` defaults with a member's `[package.metadata.osc]`
+/// keys laid over them. Tables such as `profiles` are merged key by key.
+pub fn merge_metadata(defaults: &Value, overrides: &Value) -> Value {
+    let mut merged = defaults.clone();
+    if let (Some(merged), Some(overrides)) = (merged.as_table_mut(), overrides.as_table()) {
+        for (key, value) in overrides {
+            let value = match merged.get(key) {
+                Some(default) if default.is_table() && value.is_table() => {
+                    merge_metadata(default, value)
+                }
+                _ => value.clone(),
+            };
+            merged.insert(key.clone(), value);
+        }
+    }
+    merged
+}
+
+/// A `key=value` setting given on the command line as `--config key=value`. The
+/// key is a metadata key, dotted to reach into tables (`qmp.keys-delay`), and the
+/// value is TOML (`60`, `false`, `["-d", "int"]`), or a plain string when it does
+/// not parse as TOML (`emulator=qemu-system-i386`).
+pub fn parse_setting(setting: &str) -> Result<(String, Value), String> {
+    let (key, value) = setting
+        .split_once('=')
+        .ok_or_else(|| format!("--config `{}` is not a key=value setting", setting))?;
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(format!("--config `{}` has no key", setting));
+    }
+    let value = setting_value(key, value.trim()).map_err(|e| format!("--config {}", e))?;
+    Ok((String::from(key), value))
+}
+
+/// Settings from `OSC_*` variables in `vars`: `OSC_TEST_TIMEOUT=60` sets
+/// `test-timeout`, and a double underscore reaches into a table, so
+/// `OSC_QMP__KEYS_DELAY=5` sets `qmp.keys-delay`. Variables that name no osc
+/// setting belong to something else and are skipped.
+pub fn env_settings(
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<Vec<(String, Value)>, String> {
+    let mut settings = Vec::new();
+    for (name, value) in vars {
+        let Some(key) = name.strip_prefix("OSC_") else {
+            continue;
+        };
+        let key = key
+            .to_lowercase()
+            .split("__")
+            .map(|part| part.replace('_', "-"))
+            .collect::<Vec<_>>()
+            .join(".");
+        if setting_kind(&key.split('.').collect::<Vec<_>>()).is_none() {
+            continue;
+        }
+        let value = setting_value(&key, &value).map_err(|e| format!("{}: {}", name, e))?;
+        settings.push((key, value));
+    }
+    settings.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(settings)
+}
+
+/// What a setting's value has to be.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Bool,
+    Integer,
+    String,
+    /// A list of strings; a plain string is split on whitespace.
+    Strings,
+    /// Megabytes or a size string.
+    Memory,
+    Array,
+    Table,
+}
+
+/// The kind of value `keys` takes, `None` for keys osc does not read.
+fn setting_kind(keys: &[&str]) -> Option<Kind> {
+    match keys {
+        ["run-args" | "test-args" | "bochs-args" | "cloud-hypervisor-args" | "skip"] => {
+            Some(Kind::Strings)
+        }
+        ["strip" | "symbolize" | "detect-triple-faults"] => Some(Kind::Bool),
+        ["test-success-exit-code" | "test-timeout" | "test-jobs"] => Some(Kind::Integer),
+        ["test-lib-marker" | "emulator" | "runner-backend" | "accel" | "tcg-cpu"] => {
+            Some(Kind::String)
+        }
+        ["disks"] => Some(Kind::Array),
+        ["qmp" | "profiles" | "cargo-profiles"] => Some(Kind::Table),
+        ["qmp", "on-timeout" | "on-panic" | "send-keys"] => Some(Kind::Strings),
+        ["qmp", "keys-delay"] | ["qmp", "memory", "address" | "size"] => Some(Kind::Integer),
+        ["qmp", "memory"] => Some(Kind::Table),
+        ["profiles", _] => Some(Kind::Table),
+        ["profiles", _, "inherits"] => Some(Kind::String),
+        ["profiles", _, key] => machine_kind(key),
+        ["cargo-profiles", _] => Some(Kind::Table),
+        ["cargo-profiles", _, "cargo-profiles", ..] => None,
+        ["cargo-profiles", _, rest @ ..] => setting_kind(rest),
+        [key] => machine_kind(key),
+        _ => None,
+    }
+}
+
+fn machine_kind(key: &str) -> Option<Kind> {
+    match key {
+        "memory" => Some(Kind::Memory),
+        "smp" => Some(Kind::Integer),
+        "machine" | "cpu" | "display" | "debug-log" => Some(Kind::String),
+        "devices" | "debug" | "args" => Some(Kind::Strings),
+        "network" | "drives" => Some(Kind::Array),
+        _ => None,
+    }
+}
+
+/// `value` read as TOML, or as a plain string when it does not parse, checked
+/// against what `key` takes.
+fn setting_value(key: &str, value: &str) -> Result<Value, String> {
+    let keys: Vec<&str> = key.split('.').collect();
+    let kind = setting_kind(&keys).ok_or_else(|| format!("`{}` is not an osc setting", key))?;
+    let parsed = match toml::from_str::<toml::Table>(&format!("value = {}", value)) {
+        Ok(mut table) => table.remove("value").unwrap(),
+        Err(_) => Value::String(String::from(value)),
+    };
+    match (kind, parsed) {
+        (Kind::Bool, value @ Value::Boolean(_))
+        | (Kind::Integer | Kind::Memory, value @ Value::Integer(_))
+        | (Kind::String | Kind::Memory, value @ Value::String(_))
+        | (Kind::Array, value @ Value::Array(_))
+        | (Kind::Table, value @ Value::Table(_)) => Ok(value),
+        // `tcg-cpu=486` is still a name, not a number.
+        (Kind::String, _) => Ok(Value::String(String::from(value))),
+        (Kind::Strings, Value::String(words)) => Ok(Value::Array(
+            words
+                .split_whitespace()
+                .map(|word| Value::String(String::from(word)))
+                .collect(),
+        )),
+        (Kind::Strings, Value::Array(array)) if array.iter().all(Value::is_str) => {
+            Ok(Value::Array(array))
+        }
+        (kind, _) => {
+            let expected = match kind {
+                Kind::Bool => "true or false",
+                Kind::Integer => "an integer",
+                Kind::Strings => "a list of strings",
+                Kind::Memory => "megabytes or a size string",
+                Kind::Array => "an array",
+                Kind::Table => "a table",
+                Kind::String => unreachable!(),
+            };
+            Err(format!("`{}` must be {}, not `{}`", key, expected, value))
+        }
+    }
+}
+
+/// `osc` metadata with `settings` set on it, later settings winning. They also
+/// win over `cargo-profiles`, so they are set in each of those too. From lowest
+/// to highest precedence osc reads `[workspace.metadata.osc]`,
+/// `[package.metadata.osc]`, the cargo profile's `cargo-profiles.<name>`,
+/// `OSC_*` variables and `--config`. Run profiles picked with `--run-profile`
+/// still apply over the machine settings, unless a setting names them
+/// (`profiles.smp4.memory`).
+pub fn apply_settings(osc: &Value, settings: &[(String, Value)]) -> Value {
+    let mut osc = match osc {
+        Value::Table(_) => osc.clone(),
+        _ => Value::Table(Default::default()),
+    };
+    for (key, value) in settings {
+        let keys: Vec<&str> = key.split('.').collect();
+        set(&mut osc, &keys, value.clone());
+        if keys[0] == "cargo-profiles" {
+            continue;
+        }
+        if let Some(cargo_profiles) = osc.get_mut("cargo-profiles").and_then(Value::as_table_mut) {
+            for (_, profile) in cargo_profiles.iter_mut() {
+                set(profile, &keys, value.clone());
+            }
+        }
+    }
+    osc
+}
+
+/// Sets `keys` in `table`, creating the tables on the way.
+fn set(table: &mut Value, keys: &[&str], value: Value) {
+    let Some(table) = table.as_table_mut() else {
+        return;
+    };
+    match keys {
+        [] => {}
+        [key] => {
+            table.insert(String::from(*key), value);
+        }
+        [key, rest @ ..] => {
+            let entry = table
+                .entry(String::from(*key))
+                .or_insert_with(|| Value::Table(Default::default()));
+            if !entry.is_table() {
+                *entry = Value::Table(Default::default());
+            }
+            set(entry, rest, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(words: &[&str]) -> Value {
+        Value::Array(words.iter().map(|word| Value::from(*word)).collect())
+    }
+
+    #[test]
+    fn parses_toml_and_plain_string_values() {
+        assert_eq!(
+            parse_setting("test-timeout=60").unwrap(),
+            (String::from("test-timeout"), Value::Integer(60))
+        );
+        assert_eq!(
+            parse_setting("emulator = qemu-system-i386").unwrap(),
+            (String::from("emulator"), Value::from("qemu-system-i386"))
+        );
+        assert_eq!(parse_setting("tcg-cpu=486").unwrap().1, Value::from("486"));
+        assert_eq!(
+            parse_setting("qmp.keys-delay=5").unwrap(),
+            (String::from("qmp.keys-delay"), Value::Integer(5))
+        );
+    }
+
+    #[test]
+    fn splits_plain_string_args_on_whitespace() {
+        assert_eq!(
+            parse_setting("run-args=-d int").unwrap().1,
+            strings(&["-d", "int"])
+        );
+        assert_eq!(
+            parse_setting(r#"test-args=["-m", "512M"]"#).unwrap().1,
+            strings(&["-m", "512M"])
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_wrong_types() {
+        assert!(parse_setting("timeout=60")
+            .unwrap_err()
+            .contains("`timeout` is not an osc setting"));
+        assert!(parse_setting("test-timeout=soon")
+            .unwrap_err()
+            .starts_with("--config `test-timeout` must be an integer"));
+        assert!(parse_setting("strip=1").is_err());
+        assert!(parse_setting("qmp.unknown=1").is_err());
+        assert!(parse_setting("no-equals-sign").is_err());
+        assert!(parse_setting("=1").is_err());
+    }
+
+    #[test]
+    fn reads_osc_variables_and_skips_unknown_ones() {
+        let vars = [
+            ("OSC_VERSION", "1"),
+            ("OSC_TEST_TIMEOUT", "60"),
+            ("OSC_QMP__KEYS_DELAY", "5"),
+            ("PATH", "/bin"),
+        ]
+        .map(|(name, value)| (String::from(name), String::from(value)));
+        assert_eq!(
+            env_settings(vars).unwrap(),
+            [
+                (String::from("qmp.keys-delay"), Value::Integer(5)),
+                (String::from("test-timeout"), Value::Integer(60)),
+            ]
+        );
+        let vars = [(String::from("OSC_STRIP"), String::from("yes"))];
+        assert!(env_settings(vars)
+            .unwrap_err()
+            .starts_with("OSC_STRIP: `strip` must be true or false"));
+    }
+
+    #[test]
+    fn rejects_numbers_out_of_range() {
+        let config = |setting: &str| {
+            let settings = [parse_setting(setting).unwrap()];
+            OscConfig::from_metadata(&apply_settings(
+                &Value::Table(Default::default()),
+                &settings,
+            ))
+        };
+        assert!(config("test-timeout=-1").is_err());
+        assert!(config("test-timeout=0").is_err());
+        assert_eq!(config("test-timeout=60").unwrap().test_timeout, Some(60));
+        assert!(config("test-success-exit-code=4294967296").is_err());
+        assert!(config("qmp.keys-delay=-1").is_err());
+        assert!(config("qmp.memory={ address = -1, size = 16 }").is_err());
+        assert!(config("smp=-2").is_err());
+        assert!(config("accel=tgc").is_err());
+        assert_eq!(config("accel=tcg").unwrap().accel, "tcg");
+    }
+
+    #[test]
+    fn checks_keys_inside_profiles() {
+        assert_eq!(
+            parse_setting("profiles.smp4.memory=1G").unwrap().1,
+            Value::from("1G")
+        );
+        assert!(parse_setting("profiles.smp4.strip=false").is_err());
+        assert_eq!(
+            parse_setting("cargo-profiles.release.strip=false")
+                .unwrap()
+                .1,
+            Value::Boolean(false)
+        );
+        assert!(parse_setting("cargo-profiles.release.strip=no").is_err());
+    }
+
+    #[test]
+    fn later_settings_win_and_reach_cargo_profiles() {
+        let osc: Value = toml::from_str(
+            r#"
+            test-timeout = 10
+            [qmp]
+            keys-delay = 2
+            [cargo-profiles.release]
+            test-timeout = 20
+            "#,
+        )
+        .unwrap();
+        let settings = vec![
+            parse_setting("test-timeout=30").unwrap(),
+            parse_setting("test-timeout=40").unwrap(),
+            parse_setting("qmp.keys-delay=5").unwrap(),
+            parse_setting("profiles.small.memory=64").unwrap(),
+        ];
+        let applied = apply_settings(&osc, &settings);
+        assert_eq!(applied["test-timeout"], Value::Integer(40));
+        assert_eq!(applied["qmp"]["keys-delay"], Value::Integer(5));
+        assert_eq!(applied["profiles"]["small"]["memory"], Value::Integer(64));
+        let release = &applied["cargo-profiles"]["release"];
+        assert_eq!(release["test-timeout"], Value::Integer(40));
+        assert_eq!(release["qmp"]["keys-delay"], Value::Integer(5));
+
+        let config = OscConfig::from_metadata(&applied).unwrap();
+        assert_eq!(config.test_timeout, Some(40));
+        assert_eq!(config.cargo_profiles["release"].test_timeout, Some(40));
+    }
+
+    #[test]
+    fn cargo_profile_settings_stay_in_their_profile() {
+        let settings = vec![parse_setting("cargo-profiles.release.strip=false").unwrap()];
+        let applied = apply_settings(&Value::Table(Default::default()), &settings);
+        assert!(applied.get("strip").is_none());
+        assert_eq!(
+            applied["cargo-profiles"]["release"]["strip"],
+            Value::Boolean(false)
+        );
+    }
+}
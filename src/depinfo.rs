@@ -0,0 +1,239 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// One `targets: prerequisites` rule of a Makefile-style dep-info file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub targets: Vec<PathBuf>,
+    pub prerequisites: Vec<PathBuf>,
+}
+
+/// A `.d` dep-info file rustc writes next to every artifact: the files it
+/// produced as targets, and the sources they were built from.
+#[derive(Debug, Clone, Default)]
+pub struct DepInfo {
+    pub rules: Vec<Rule>,
+}
+
+/// What kind of crate an output is, from its file name.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CrateType {
+    StaticLib,
+    Rlib,
+    /// A bin or test crate linked by osc instead of rustc (`--emit obj`).
+    Object,
+    Other,
+}
+
+impl CrateType {
+    pub fn of(output: &Path) -> Self {
+        match output.extension().and_then(|extension| extension.to_str()) {
+            Some("a") => CrateType::StaticLib,
+            Some("rlib") => CrateType::Rlib,
+            Some("o") => CrateType::Object,
+            _ => CrateType::Other,
+        }
+    }
+}
+
+impl DepInfo {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    /// Parses dep-info the way make reads it: `\` at the end of a line continues
+    /// it, `\ `, `\:` and `\#` escape, `$$` is a `$`, and `#` starts a comment
+    /// (rustc writes `# env-dep:` lines).
+    pub fn parse(text: &str) -> Self {
+        let mut rules = Vec::new();
+        let mut logical = String::new();
+        for line in text.lines() {
+            if let Some(continued) = line.strip_suffix('\\') {
+                if !continued.ends_with('\\') {
+                    logical.push_str(continued);
+                    logical.push(' ');
+                    continue;
+                }
+            }
+            logical.push_str(line);
+            if let Some(rule) = parse_rule(&logical) {
+                rules.push(rule);
+            }
+            logical.clear();
+        }
+        if let Some(rule) = parse_rule(&logical) {
+            rules.push(rule);
+        }
+        DepInfo { rules }
+    }
+
+    /// The crate root: the first source of the first rule.
+    pub fn root_source(&self) -> Option<&Path> {
+        self.rules
+            .iter()
+            .find(|rule| !rule.prerequisites.is_empty())
+            .map(|rule| rule.prerequisites[0].as_path())
+    }
+
+    /// Every source file the artifact depends on.
+    pub fn sources(&self) -> impl Iterator<Item = &Path> {
+        self.rules
+            .iter()
+            .flat_map(|rule| rule.prerequisites.iter().map(PathBuf::as_path))
+    }
+
+    /// Files the compilation produced, without the dep-info file itself.
+    pub fn outputs(&self) -> impl Iterator<Item = &Path> {
+        self.rules
+            .iter()
+            .filter(|rule| !rule.prerequisites.is_empty())
+            .flat_map(|rule| rule.targets.iter().map(PathBuf::as_path))
+            .filter(|target| target.extension() != Some(OsStr::new("d")))
+    }
+
+    pub fn outputs_of_type(&self, crate_type: CrateType) -> impl Iterator<Item = &Path> {
+        self.outputs()
+            .filter(move |output| CrateType::of(output) == crate_type)
+    }
+}
+
+fn parse_rule(line: &str) -> Option<Rule> {
+    let words = split_words(line);
+    // Comments and blank lines.
+    let separator = words.iter().position(|(_, separator)| *separator)?;
+    let paths = |words: &[(String, bool)]| -> Vec<PathBuf> {
+        words
+            .iter()
+            .filter(|(word, _)| !word.is_empty())
+            .map(|(word, _)| PathBuf::from(word))
+            .collect()
+    };
+    let (targets, prerequisites) = words.split_at(separator);
+    Some(Rule {
+        targets: paths(targets),
+        prerequisites: paths(&prerequisites[1..]),
+    })
+}
+
+/// Splits a logical line into unescaped words. The rule separator is its own
+/// word marked `true`; a `:` only separates when followed by whitespace or the
+/// end of the line, so `C:\` style paths stay whole.
+fn split_words(line: &str) -> Vec<(String, bool)> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut chars = line.chars().peekable();
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' if matches!(chars.peek(), Some(' ' | ':' | '#')) => {
+                word.push(chars.next().unwrap());
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                word.push(chars.next().unwrap());
+            }
+            '#' => break,
+            ':' if words.iter().all(|(_, separator)| !separator)
+                && chars.peek().is_none_or(|next| next.is_whitespace()) =>
+            {
+                words.push((std::mem::take(&mut word), false));
+                words.push((String::new(), true));
+            }
+            char if char.is_whitespace() => words.push((std::mem::take(&mut word), false)),
+            char => word.push(char),
+        }
+    }
+    words.push((word, false));
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    // What rustc writes for a lib built as a staticlib and an rlib.
+    const LIB: &str = "\
+/k/target/x86_64-os/debug/deps/kern-1f2e.d: src/lib.rs src/my\\ module.rs
+
+/k/target/x86_64-os/debug/deps/libkern-1f2e.a: src/lib.rs src/my\\ module.rs
+
+/k/target/x86_64-os/debug/deps/libkern-1f2e.rlib: src/lib.rs src/my\\ module.rs
+
+src/lib.rs:
+src/my\\ module.rs:
+
+# env-dep:CARGO_PKG_NAME=kern
+# env-dep:OSC_BUILD=a\\ b
+";
+
+    #[test]
+    fn reads_rustc_dep_info() {
+        let dep_info = DepInfo::parse(LIB);
+        assert_eq!(dep_info.root_source(), Some(Path::new("src/lib.rs")));
+        assert_eq!(
+            dep_info.outputs().collect::<Vec<_>>(),
+            [
+                Path::new("/k/target/x86_64-os/debug/deps/libkern-1f2e.a"),
+                Path::new("/k/target/x86_64-os/debug/deps/libkern-1f2e.rlib"),
+            ]
+        );
+        assert_eq!(dep_info.outputs_of_type(CrateType::StaticLib).count(), 1);
+        assert!(dep_info
+            .sources()
+            .all(|source| source == Path::new("src/lib.rs")
+                || source == Path::new("src/my module.rs")));
+    }
+
+    #[test]
+    fn skips_env_dep_comments() {
+        let dep_info = DepInfo::parse(LIB);
+        // Three rules with sources, two phony source rules.
+        assert_eq!(dep_info.rules.len(), 5);
+        assert!(dep_info
+            .sources()
+            .all(|source| !source.to_string_lossy().contains("env-dep")));
+    }
+
+    #[test]
+    fn reads_a_rule_with_several_targets() {
+        let dep_info = DepInfo::parse(
+            "/k/deps/kern-1f2e.o /k/deps/libkern-1f2e.a: src/lib.rs \\\n    src/boot.rs\n",
+        );
+        assert_eq!(
+            dep_info.rules,
+            [Rule {
+                targets: paths(&["/k/deps/kern-1f2e.o", "/k/deps/libkern-1f2e.a"]),
+                prerequisites: paths(&["src/lib.rs", "src/boot.rs"]),
+            }]
+        );
+        assert_eq!(dep_info.outputs_of_type(CrateType::Object).count(), 1);
+        assert_eq!(dep_info.outputs_of_type(CrateType::StaticLib).count(), 1);
+    }
+
+    #[test]
+    fn keeps_drive_letter_paths_whole() {
+        let dep_info = DepInfo::parse(
+            "C:\\k\\target\\debug\\deps\\libkern-1f2e.a: C:\\k\\src\\lib.rs C:\\k\\src\\my\\ module.rs\n",
+        );
+        assert_eq!(
+            dep_info.rules,
+            [Rule {
+                targets: paths(&["C:\\k\\target\\debug\\deps\\libkern-1f2e.a"]),
+                prerequisites: paths(&["C:\\k\\src\\lib.rs", "C:\\k\\src\\my module.rs"]),
+            }]
+        );
+    }
+
+    #[test]
+    fn unescapes_dollars_colons_and_hashes() {
+        let dep_info = DepInfo::parse("out.a: src/$$x.rs src/a\\:b.rs src/\\#c.rs\n");
+        assert_eq!(
+            dep_info.rules[0].prerequisites,
+            paths(&["src/$x.rs", "src/a:b.rs", "src/#c.rs"])
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use toml::Value;
+
+use crate::machine::Drive;
+
+/// An extra disk from `[[package.metadata.osc.disks]]`: either a host directory
+/// packed into a FAT image, or an existing raw image.
+#[derive(Debug, Clone)]
+pub struct DataDisk {
+    /// Directory packed into the image, relative to the kernel package.
+    pub source: Option<PathBuf>,
+    /// Existing image attached as it is, relative to the kernel package.
+    pub file: Option<PathBuf>,
+    /// `ide`, `ahci`, `virtio`, `scsi` or `floppy`.
+    pub interface: String,
+    /// Size of a generated image in MiB.
+    pub size: u64,
+}
+
+impl DataDisk {
+    pub fn from_metadata(disk: &Value) -> Result<Self, String> {
+        let path = |key: &str| disk.get(key).and_then(Value::as_str).map(PathBuf::from);
+        let (source, file) = (path("source"), path("file"));
+        if source.is_some() == file.is_some() {
+            return Err(String::from(
+                "disks need either a `source` directory or a `file`",
+            ));
+        }
+        match disk.get("filesystem").and_then(Value::as_str) {
+            None | Some("fat") => {}
+            Some(filesystem) => {
+                return Err(format!(
+                    "cannot generate `{}` images, only `fat` is supported",
+                    filesystem
+                ))
+            }
+        }
+        let interface = disk
+            .get("interface")
+            .and_then(Value::as_str)
+            .unwrap_or("ide")
+            .to_string();
+        if !["ide", "ahci", "virtio", "scsi", "floppy"].contains(&interface.as_str()) {
+            return Err(format!("unknown drive interface `{}`", interface));
+        }
+        let size = match disk.get("size").map(Value::as_integer) {
+            Some(Some(size)) if size > 0 => size as u64,
+            Some(_) => return Err(String::from("disk `size` must be a positive number of MiB")),
+            None => 16,
+        };
+        Ok(Self {
+            source,
+            file,
+            interface,
+            size,
+        })
+    }
+}
+
+/// Builds every disk into `out_dir` as `disk<N>.img`, returning the drives to
+/// attach. Images are regenerated on each build so they match `source`.
+pub fn build_images(
+    disks: &[DataDisk],
+    kernel_dir: &Path,
+    out_dir: &Path,
+) -> Result<Vec<Drive>, Box<dyn Error>> {
+    let mut drives = Vec::new();
+    for (index, disk) in disks.iter().enumerate() {
+        let image = out_dir.join(format!("disk{}.img", index));
+        if let Some(file) = &disk.file {
+            let file = kernel_dir.join(file);
+            fs::copy(&file, &image).map_err(|e| format!("{} {}", file.display(), e))?;
+        } else if let Some(source) = &disk.source {
+            let source = kernel_dir.join(source);
+            fat_image(&source, &image, disk.size)
+                .map_err(|e| format!("Cannot pack {} {}", source.display(), e))?;
+        }
+        drives.push(Drive {
+            file: image.to_string_lossy().into_owned(),
+            format: String::from("raw"),
+            interface: disk.interface.clone(),
+            readonly: false,
+        });
+    }
+    Ok(drives)
+}
+
+/// Writes a `size` MiB FAT image holding everything in `source`.
+fn fat_image(source: &Path, image: &Path, size: u64) -> io::Result<()> {
+    if !source.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "is not a directory",
+        ));
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(image)?;
+    file.set_len(size * 1024 * 1024)?;
+    fatfs::format_volume(&mut file, fatfs::FormatVolumeOptions::new())?;
+    let filesystem = fatfs::FileSystem::new(file, fatfs::FsOptions::new())?;
+    copy_into(source, &filesystem.root_dir())?;
+    filesystem.unmount()
+}
+
+fn copy_into<T: fatfs::ReadWriteSeek>(from: &Path, to: &fatfs::Dir<T>) -> io::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if entry.file_type()?.is_dir() {
+            copy_into(&entry.path(), &to.create_dir(&name)?)?;
+        } else {
+            let mut file = to.create_file(&name)?;
+            file.truncate()?;
+            file.write_all(&fs::read(entry.path())?)?;
+        }
+    }
+    Ok(())
+}
+
+static RUN: AtomicUsize = AtomicUsize::new(0);
+
+/// Copies of the built images for a single run, so a kernel writing to its disks
+/// never sees what an earlier or concurrent run left behind. Removed on drop.
+pub struct FreshDisks {
+    pub drives: Vec<Drive>,
+}
+
+impl FreshDisks {
+    pub fn copy(images: &[Drive]) -> io::Result<Self> {
+        let run = RUN.fetch_add(1, Ordering::SeqCst);
+        let mut fresh = FreshDisks { drives: Vec::new() };
+        for image in images {
+            let original = Path::new(&image.file);
+            let copy = original.with_extension(format!("{}-{}.img", process::id(), run));
+            fs::copy(original, &copy)?;
+            fresh.drives.push(Drive {
+                file: copy.to_string_lossy().into_owned(),
+                ..image.clone()
+            });
+        }
+        Ok(fresh)
+    }
+}
+
+impl Drop for FreshDisks {
+    fn drop(&mut self) {
+        for drive in &self.drives {
+            fs::remove_file(&drive.file).ok();
+        }
+    }
+}
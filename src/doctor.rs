@@ -0,0 +1,261 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use toml::Value;
+
+use crate::cargo_config::CargoConfig;
+use crate::emulator;
+use crate::project::Project;
+use crate::target::Target;
+
+/// Directories distributions install GRUB's BIOS platform modules to.
+const GRUB_MODULE_DIRS: [&str; 3] = [
+    "/usr/lib/grub/i386-pc",
+    "/usr/lib/grub2/i386-pc",
+    "/usr/share/grub2/i386-pc",
+];
+
+/// `osc doctor`: checks every tool osc runs and the files `build_iso` expects,
+/// printing how to fix whatever is missing. Returns whether everything was found.
+pub fn doctor(current_dir: &Path, package: Option<&str>, settings: &[(String, Value)]) -> bool {
+    let mut healthy = true;
+    let mut report = |found: Result<String, String>| match found {
+        Ok(found) => println!("ok       {}", found),
+        Err(fix) => {
+            println!("MISSING  {}", fix);
+            healthy = false;
+        }
+    };
+
+    let project = Project::discover_with_settings(current_dir, package, settings);
+    report(
+        project
+            .as_ref()
+            .map(|project| format!("project {}", project.kernel_dir.display()))
+            .map_err(|e| format!("project: {}; `osc init` sets one up", e)),
+    );
+
+    report(tool("cargo", "--version", "install Rust with rustup"));
+    report(tool("nasm", "-v", "install nasm, e.g. `apt install nasm`"));
+    report(tool("ar", "--version", "install binutils"));
+    report(tool("ld", "--version", "install binutils"));
+    if project
+        .as_ref()
+        .map_or(true, |project| project.osc_config.strip)
+    {
+        report(tool(
+            "strip",
+            "--version",
+            "install binutils, or set `strip = false`",
+        ));
+    }
+    report(tool(
+        "grub-mkrescue",
+        "--version",
+        "install GRUB, e.g. `apt install grub-common grub-pc-bin`",
+    ));
+    report(tool(
+        "xorriso",
+        "-version",
+        "grub-mkrescue needs it, e.g. `apt install xorriso`",
+    ));
+    report(
+        GRUB_MODULE_DIRS
+            .iter()
+            .map(PathBuf::from)
+            .find(|dir| dir.join("multiboot2.mod").is_file())
+            .map(|dir| format!("GRUB i386-pc modules in {}", dir.display()))
+            .ok_or_else(|| {
+                String::from(
+                    "GRUB i386-pc modules, grub-mkrescue cannot make a BIOS image \
+                     without them; e.g. `apt install grub-pc-bin`",
+                )
+            }),
+    );
+
+    let project = match project {
+        Ok(project) => project,
+        Err(_) => return false,
+    };
+    match project.osc_config.runner_backend.as_str() {
+        "bochs" => report(tool(
+            project.osc_config.emulator.as_deref().unwrap_or("bochs"),
+            "--help",
+            "install Bochs, e.g. `apt install bochs bochsbios vgabios`",
+        )),
+        "cloud-hypervisor" => report(tool(
+            project
+                .osc_config
+                .emulator
+                .as_deref()
+                .unwrap_or("cloud-hypervisor"),
+            "--version",
+            "install Cloud Hypervisor from its GitHub releases",
+        )),
+        _ => {
+            report(emulator::emulator(&project).and_then(|qemu| {
+                tool(
+                    &qemu,
+                    "--version",
+                    "install QEMU, e.g. `apt install qemu-system-x86`, or set `emulator`",
+                )
+            }));
+            let arch = project
+                .target
+                .as_ref()
+                .and_then(emulator::target_arch)
+                .unwrap_or_else(|| String::from("x86_64"));
+            if emulator::kvm_usable(&arch) {
+                println!("ok       KVM");
+            } else {
+                println!("note     KVM is not usable, QEMU will fall back to slower TCG");
+            }
+        }
+    }
+
+    let kernel_dir = &project.kernel_dir;
+    let file = |path: &str, fix: &str| {
+        if kernel_dir.join(path).exists() {
+            Ok(String::from(path))
+        } else {
+            Err(format!("{}: {}", path, fix))
+        }
+    };
+    report(staticlib(kernel_dir));
+    report(obj_emit(current_dir));
+    report(file(
+        "linker.ld",
+        "the linker script `ld -T` uses; `osc init` writes one",
+    ));
+    report(file(
+        "src/boot",
+        "the directory of .asm files nasm assembles; `osc init` writes a multiboot2 header",
+    ));
+    report(file(
+        "iso/boot/grub/grub.cfg",
+        "the GRUB config packed into the ISO; `osc init` writes one",
+    ));
+    report(match &project.target {
+        None => Err(String::from(
+            "build.target in .cargo/config.toml: osc needs the kernel's target",
+        )),
+        // Built-in triples have no spec file.
+        Some(Target::Triple(triple)) => Ok(format!("target {}", triple)),
+        Some(Target::Spec(spec)) if spec.is_file() => Ok(format!("target spec {}", spec.display())),
+        Some(Target::Spec(spec)) => Err(format!(
+            "target spec {}: the target points at a file that does not exist",
+            spec.display()
+        )),
+    });
+    healthy
+}
+
+/// Whether the kernel's `[lib]` builds the static library `build_iso` links.
+fn staticlib(kernel_dir: &Path) -> Result<String, String> {
+    let missing = "[lib] crate-type = [\"staticlib\"] in Cargo.toml: the kernel is \
+                   linked from the lib's static library; `osc init` adds it";
+    let manifest = fs::read_to_string(kernel_dir.join("Cargo.toml"))
+        .ok()
+        .and_then(|text| toml::from_str::<Value>(&text).ok())
+        .ok_or_else(|| String::from(missing))?;
+    let staticlib = manifest
+        .get("lib")
+        .and_then(|lib| lib.get("crate-type"))
+        .and_then(Value::as_array)
+        .is_some_and(|crate_types| {
+            crate_types
+                .iter()
+                .any(|crate_type| crate_type.as_str() == Some("staticlib"))
+        });
+    if staticlib {
+        Ok(String::from("[lib] crate-type staticlib"))
+    } else {
+        Err(String::from(missing))
+    }
+}
+
+/// Whether rustc is told to emit object files, which test kernels are linked
+/// from. Cargo takes `RUSTFLAGS` over any config, and `target.<cfg>.rustflags`
+/// over `build.rustflags`.
+fn obj_emit(current_dir: &Path) -> Result<String, String> {
+    let missing = "--emit=obj,link in build.rustflags: test kernels are linked from \
+                   rustc's object files; `osc init` adds it";
+    let flag_lists: Vec<Vec<String>> = match env::var("RUSTFLAGS") {
+        Ok(flags) => vec![flags.split_whitespace().map(String::from).collect()],
+        Err(_) => {
+            let config = CargoConfig::discover(current_dir).map_err(|e| e.to_string())?;
+            let target_flags: Vec<Vec<String>> = config
+                .get(&["target"])
+                .and_then(|(targets, _)| targets.as_table())
+                .into_iter()
+                .flatten()
+                .filter_map(|(_, target)| target.get("rustflags").map(rustflags))
+                .collect();
+            if target_flags.is_empty() {
+                config
+                    .get(&["build", "rustflags"])
+                    .map(|(flags, _)| vec![rustflags(flags)])
+                    .unwrap_or_default()
+            } else {
+                target_flags
+            }
+        }
+    };
+    if !flag_lists.is_empty() && flag_lists.iter().all(|flags| emits_obj(flags)) {
+        Ok(String::from("rustc emits object files"))
+    } else {
+        Err(String::from(missing))
+    }
+}
+
+/// Rustflags as cargo reads them: an array, or a string split on whitespace.
+fn rustflags(flags: &Value) -> Vec<String> {
+    match flags {
+        Value::String(flags) => flags.split_whitespace().map(String::from).collect(),
+        Value::Array(flags) => flags
+            .iter()
+            .filter_map(|flag| flag.as_str().map(String::from))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn emits_obj(flags: &[String]) -> bool {
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        let kinds = match flag.strip_prefix("--emit=") {
+            Some(kinds) => kinds,
+            None if flag == "--emit" => flags.next().map_or("", String::as_str),
+            None => continue,
+        };
+        if kinds
+            .split(',')
+            .any(|kind| kind.split('=').next() == Some("obj"))
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// The first line `program flag` prints, usually its version.
+fn tool(program: &str, flag: &str, fix: &str) -> Result<String, String> {
+    let output = Command::new(program)
+        .arg(flag)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|_| format!("{}: {}", program, fix))?;
+    let text = if output.stdout.is_empty() {
+        output.stderr
+    } else {
+        output.stdout
+    };
+    let text = String::from_utf8_lossy(&text);
+    Ok(text
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .map(|line| format!("{} ({})", program, line.trim()))
+        .unwrap_or_else(|| String::from(program)))
+}
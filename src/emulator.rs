@@ -0,0 +1,111 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+
+use serde_json::Value;
+
+use crate::project::Project;
+use crate::target::Target;
+
+/// `arch` of the target spec, or the architecture part of a target triple.
+pub fn target_arch(target: &Target) -> Option<String> {
+    match target {
+        Target::Spec(spec) => {
+            let spec: Value = serde_json::from_str(&fs::read_to_string(spec).ok()?).ok()?;
+            spec.get("arch")?.as_str().map(String::from)
+        }
+        Target::Triple(triple) => triple.split('-').next().map(String::from),
+    }
+}
+
+/// The QEMU system emulator for a rustc `arch` value.
+pub fn qemu_for_arch(arch: &str) -> Option<&'static str> {
+    match arch {
+        "x86_64" => Some("qemu-system-x86_64"),
+        "x86" | "i386" | "i586" | "i686" => Some("qemu-system-i386"),
+        "aarch64" => Some("qemu-system-aarch64"),
+        "riscv64" | "riscv64gc" | "riscv64imac" => Some("qemu-system-riscv64"),
+        _ => None,
+    }
+}
+
+/// Arm and RISC-V QEMU have no default machine, so osc boots them on `virt`
+/// unless the run arguments pick one.
+pub fn default_machine(arch: &str) -> Option<&'static str> {
+    match arch {
+        "aarch64" | "riscv64" | "riscv64gc" | "riscv64imac" => Some("virt"),
+        _ => None,
+    }
+}
+
+/// The emulator to boot the kernel in: the `emulator` key if set, otherwise the
+/// QEMU matching the target's architecture, defaulting to x86_64.
+pub fn emulator(project: &Project) -> Result<String, String> {
+    if let Some(emulator) = &project.osc_config.emulator {
+        return Ok(emulator.clone());
+    }
+    let arch = project.target.as_ref().and_then(target_arch);
+    match arch.as_deref() {
+        Some(arch) => qemu_for_arch(arch).map(String::from).ok_or_else(|| {
+            format!(
+                "no known emulator for arch {}, set `emulator` in [package.metadata.osc]",
+                arch
+            )
+        }),
+        None => Ok(String::from("qemu-system-x86_64")),
+    }
+}
+
+/// Whether the host can run the target's code under KVM.
+pub fn kvm_usable(target_arch: &str) -> bool {
+    let host = env::consts::ARCH;
+    let same_arch = host == target_arch || (host == "x86_64" && target_arch == "x86");
+    same_arch
+        && OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/kvm")
+            .is_ok()
+}
+
+/// `-accel` and `-cpu` arguments for QEMU: KVM with the host CPU when `/dev/kvm` is
+/// accessible and the target matches the host, TCG with `tcg-cpu` otherwise.
+/// `accel = "kvm"` or `"tcg"` skips the detection, and arguments the user already
+/// passed are left alone.
+pub fn accel_args(project: &Project, args: &[String]) -> Vec<String> {
+    let osc_config = &project.osc_config;
+    let has = |flags: &[&str]| args.iter().any(|arg| flags.contains(&arg.as_str()));
+    // Acceleration can also be chosen through `-machine accel=...`.
+    if has(&["-accel", "-enable-kvm"]) || args.iter().any(|arg| arg.contains("accel=")) {
+        return Vec::new();
+    }
+    let arch = project
+        .target
+        .as_ref()
+        .and_then(target_arch)
+        .unwrap_or_else(|| String::from("x86_64"));
+    let kvm = match osc_config.accel.as_str() {
+        "kvm" => true,
+        "tcg" => false,
+        _ => kvm_usable(&arch),
+    };
+
+    let mut accel = Vec::new();
+    if kvm {
+        accel.push(String::from("-accel"));
+        accel.push(String::from("kvm"));
+        if !has(&["-cpu"]) {
+            accel.push(String::from("-cpu"));
+            accel.push(String::from("host"));
+        }
+    } else {
+        accel.push(String::from("-accel"));
+        accel.push(String::from("tcg"));
+        if let Some(cpu) = &osc_config.tcg_cpu {
+            if !has(&["-cpu"]) {
+                accel.push(String::from("-cpu"));
+                accel.push(cpu.clone());
+            }
+        }
+    }
+    accel
+}
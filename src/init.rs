@@ -0,0 +1,469 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use toml_edit::{value, Array, Document, Item, Table};
+
+const LINKER_LD: &str = "\
+ENTRY(start)
+
+SECTIONS {
+    . = 1M;
+
+    .boot : {
+        /* GRUB only looks for the multiboot2 header in the first 32 KiB. */
+        KEEP(*(.multiboot_header))
+    }
+    .text : { *(.text .text.*) }
+    .rodata : { *(.rodata .rodata.*) }
+    .data : { *(.data .data.*) }
+    .bss : { *(.bss .bss.*) }
+}
+";
+
+const MULTIBOOT_HEADER_ASM: &str = "\
+section .multiboot_header
+header_start:
+    dd 0xe85250d6                ; multiboot2 magic
+    dd 0                         ; i386 protected mode
+    dd header_end - header_start
+    dd 0x100000000 - (0xe85250d6 + 0 + (header_end - header_start))
+
+    ; end tag
+    dw 0
+    dw 0
+    dd 8
+header_end:
+";
+
+const BOOT_ASM: &str = "\
+global start
+extern long_mode_start
+
+section .text
+bits 32
+start:
+    mov esp, stack_top
+    mov edi, ebx                 ; multiboot2 info, first argument of rust_main
+
+    call set_up_page_tables
+    call enable_paging
+    lgdt [gdt64.pointer]
+    jmp gdt64.code:long_mode_start
+
+; Identity maps the first GiB with 2 MiB pages.
+set_up_page_tables:
+    mov eax, p3_table
+    or eax, 0b11
+    mov [p4_table], eax
+    mov eax, p2_table
+    or eax, 0b11
+    mov [p3_table], eax
+    mov ecx, 0
+.map_p2_table:
+    mov eax, 0x200000
+    mul ecx
+    or eax, 0b10000011
+    mov [p2_table + ecx * 8], eax
+    inc ecx
+    cmp ecx, 512
+    jne .map_p2_table
+    ret
+
+enable_paging:
+    mov eax, p4_table
+    mov cr3, eax
+    mov eax, cr4
+    or eax, 1 << 5               ; PAE
+    mov cr4, eax
+    mov ecx, 0xC0000080          ; EFER
+    rdmsr
+    or eax, 1 << 8               ; long mode
+    wrmsr
+    mov eax, cr0
+    or eax, 1 << 31              ; paging
+    mov cr0, eax
+    ret
+
+section .rodata
+gdt64:
+    dq 0
+.code: equ $ - gdt64
+    dq (1 << 43) | (1 << 44) | (1 << 47) | (1 << 53)
+.pointer:
+    dw $ - gdt64 - 1
+    dq gdt64
+
+section .bss
+align 4096
+p4_table:
+    resb 4096
+p3_table:
+    resb 4096
+p2_table:
+    resb 4096
+stack_bottom:
+    resb 4096 * 16
+stack_top:
+";
+
+const LONG_MODE_INIT_ASM: &str = "\
+global long_mode_start
+extern rust_main
+
+section .text
+bits 64
+long_mode_start:
+    mov ax, 0
+    mov ss, ax
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+
+    call rust_main
+.halt:
+    hlt
+    jmp .halt
+";
+
+const GRUB_CFG: &str = "\
+set timeout=0
+set default=0
+
+menuentry \"kernel\" {
+    multiboot2 /boot/kernel.bin
+    boot
+}
+";
+
+/// `osc init [--target <triple or spec>] [--yes] [--dry-run]`: wires osc into the
+/// crate in `current_dir`. Files that exist are only added to, never replaced, so
+/// running it again changes nothing. Every change is shown as a diff and applied
+/// only after confirmation.
+pub fn init(current_dir: &Path, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut target = String::from("x86_64-unknown-none");
+    let mut yes = false;
+    let mut dry_run = false;
+    let mut init_args = args.iter();
+    while let Some(arg) = init_args.next() {
+        match arg.as_str() {
+            "--target" => target = init_args.next().ok_or("--target needs a value")?.clone(),
+            "--yes" | "-y" => yes = true,
+            "--dry-run" => dry_run = true,
+            _ => return Err(format!("unknown init option {}", arg).into()),
+        }
+    }
+
+    let kernel_dir = current_dir
+        .ancestors()
+        .find(|dir| dir.join("Cargo.toml").is_file())
+        .ok_or("could not find Cargo.toml in this directory or any parent directory")?;
+    let mut changes: Vec<(PathBuf, Option<String>, String)> = Vec::new();
+    let mut plan = |path: PathBuf, new: String| {
+        let old = fs::read_to_string(&path).ok();
+        if old.as_deref() != Some(new.as_str()) {
+            changes.push((path, old, new));
+        }
+    };
+
+    let manifest = kernel_dir.join("Cargo.toml");
+    let mut manifest_text = with_osc_metadata(&fs::read_to_string(&manifest)?)?;
+    // Cargo refuses a `[lib]` without a source file, so a bin-only crate is left
+    // for `osc doctor` to point out.
+    if kernel_dir.join("src/lib.rs").is_file() {
+        manifest_text = with_staticlib(&manifest_text)?;
+    }
+    plan(manifest.clone(), manifest_text);
+    // Cargo reads the legacy `.cargo/config` over `config.toml` when both exist.
+    let legacy_config = kernel_dir.join(".cargo").join("config");
+    let config = if legacy_config.is_file() {
+        legacy_config
+    } else {
+        kernel_dir.join(".cargo").join("config.toml")
+    };
+    plan(
+        config.clone(),
+        with_obj_emit(&with_runner(
+            &fs::read_to_string(&config).unwrap_or_default(),
+            &target,
+        )?)?,
+    );
+    let files = [
+        ("linker.ld", LINKER_LD),
+        ("src/boot/multiboot_header.asm", MULTIBOOT_HEADER_ASM),
+        ("src/boot/boot.asm", BOOT_ASM),
+        ("src/boot/long_mode_init.asm", LONG_MODE_INIT_ASM),
+        ("iso/boot/grub/grub.cfg", GRUB_CFG),
+    ];
+    for (file, contents) in files {
+        let path = kernel_dir.join(file);
+        // The boot code is the user's once it exists.
+        if !path.exists() {
+            plan(path, String::from(contents));
+        }
+    }
+
+    if changes.is_empty() {
+        println!("{} is already set up for osc", kernel_dir.display());
+        return Ok(());
+    }
+    for (path, old, new) in &changes {
+        let path = path.strip_prefix(kernel_dir).unwrap_or(path);
+        println!("--- {}", path.display());
+        println!("+++ {}", path.display());
+        for line in diff(old.as_deref().unwrap_or_default(), new) {
+            println!("{}", line);
+        }
+    }
+    if dry_run {
+        return Ok(());
+    }
+    if !yes {
+        print!("Apply these changes? [y/N] ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().lock().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            println!("Nothing written");
+            return Ok(());
+        }
+    }
+    for (path, _, new) in &changes {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, new)?;
+        println!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+/// `manifest` with a default `[package.metadata.osc]` unless it has one.
+fn with_osc_metadata(manifest: &str) -> Result<String, Box<dyn Error>> {
+    let mut document: Document = manifest.parse()?;
+    let package = document["package"]
+        .as_table_mut()
+        .ok_or("Cargo.toml has no [package]")?;
+    if !package.contains_key("metadata") {
+        let mut metadata = Table::new();
+        metadata.set_implicit(true);
+        package.insert("metadata", Item::Table(metadata));
+    }
+    let metadata = package["metadata"]
+        .as_table_mut()
+        .ok_or("package.metadata is not a table")?;
+    if !metadata.contains_key("osc") {
+        let mut osc = Table::new();
+        // Matches the isa-debug-exit port in `test-args`: writing 16 exits with 33.
+        osc["test-success-exit-code"] = value(33);
+        osc["test-args"] = value(Array::from_iter([
+            "-device",
+            "isa-debug-exit,iobase=0xf4,iosize=0x04",
+            "-serial",
+            "stdio",
+            "-display",
+            "none",
+        ]));
+        osc["run-args"] = value(Array::from_iter(["-serial", "stdio"]));
+        osc["test-timeout"] = value(300);
+        metadata.insert("osc", Item::Table(osc));
+    }
+    Ok(document.to_string())
+}
+
+/// `manifest` with `staticlib` in `[lib] crate-type`, the static library
+/// `build_iso` links the kernel from.
+fn with_staticlib(manifest: &str) -> Result<String, Box<dyn Error>> {
+    let mut document: Document = manifest.parse()?;
+    let root = document.as_table_mut();
+    if !root.contains_key("lib") {
+        root.insert("lib", Item::Table(Table::new()));
+    }
+    let lib = root["lib"]
+        .as_table_mut()
+        .ok_or("lib in Cargo.toml is not a table")?;
+    if !lib.contains_key("crate-type") {
+        lib["crate-type"] = value(Array::new());
+    }
+    let crate_types = lib["crate-type"]
+        .as_array_mut()
+        .ok_or("lib.crate-type in Cargo.toml is not an array")?;
+    if !crate_types
+        .iter()
+        .any(|crate_type| crate_type.as_str() == Some("staticlib"))
+    {
+        crate_types.push("staticlib");
+    }
+    Ok(document.to_string())
+}
+
+/// `config` with `build.target` and an `osc runner` runner for bare-metal targets,
+/// keeping any that are already set.
+fn with_runner(config: &str, target: &str) -> Result<String, Box<dyn Error>> {
+    let mut document: Document = config.parse()?;
+    let root = document.as_table_mut();
+    for key in ["build", "target"] {
+        if !root.contains_key(key) {
+            let mut table = Table::new();
+            table.set_implicit(key == "target");
+            root.insert(key, Item::Table(table));
+        }
+    }
+    let build = root["build"]
+        .as_table_mut()
+        .ok_or("build in .cargo/config.toml is not a table")?;
+    if !build.contains_key("target") {
+        build["target"] = value(target);
+    }
+    let targets = root["target"]
+        .as_table_mut()
+        .ok_or("target in .cargo/config.toml is not a table")?;
+    let has_runner = targets
+        .iter()
+        .any(|(_, target)| target.get("runner").is_some());
+    if !has_runner {
+        let mut none = Table::new();
+        none["runner"] = value("osc runner");
+        targets.insert("cfg(target_os = \"none\")", Item::Table(none));
+    }
+    Ok(document.to_string())
+}
+
+/// `config` with `--emit=obj,link` in `build.rustflags` and every
+/// `target.<cfg>.rustflags` (which cargo reads instead), so rustc also leaves the
+/// object files osc links test kernels from. Existing flags are kept.
+fn with_obj_emit(config: &str) -> Result<String, Box<dyn Error>> {
+    let mut document: Document = config.parse()?;
+    let root = document.as_table_mut();
+    if !root.contains_key("build") {
+        root.insert("build", Item::Table(Table::new()));
+    }
+    let build = root["build"]
+        .as_table_mut()
+        .ok_or("build in .cargo/config.toml is not a table")?;
+    if !build.contains_key("rustflags") {
+        build["rustflags"] = value(Array::new());
+    }
+    add_obj_emit(&mut build["rustflags"])?;
+    if let Some(targets) = root.get_mut("target").and_then(Item::as_table_mut) {
+        for (_, target) in targets.iter_mut() {
+            let rustflags = target
+                .as_table_like_mut()
+                .and_then(|target| target.get_mut("rustflags"));
+            if let Some(rustflags) = rustflags {
+                add_obj_emit(rustflags)?;
+            }
+        }
+    }
+    Ok(document.to_string())
+}
+
+fn add_obj_emit(rustflags: &mut Item) -> Result<(), Box<dyn Error>> {
+    const EMIT: &str = "--emit=obj,link";
+    if let Some(flags) = rustflags.as_array_mut() {
+        if !flags
+            .iter()
+            .any(|flag| flag.as_str().is_some_and(|flag| flag.starts_with("--emit")))
+        {
+            flags.push(EMIT);
+        }
+    } else if let Some(flags) = rustflags.as_str() {
+        if !flags.contains("--emit") {
+            *rustflags = value(format!("{} {}", flags, EMIT).trim());
+        }
+    } else {
+        return Err("rustflags in .cargo/config.toml is not a string or an array".into());
+    }
+    Ok(())
+}
+
+/// A line diff of `old` and `new` with two lines of context around changes.
+fn diff(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    // Longest common subsequence table, filled from the end.
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || common[i][j + 1] >= common[i + 1][j]) {
+            lines.push(('+', new[j]));
+            j += 1;
+        } else {
+            lines.push(('-', old[i]));
+            i += 1;
+        }
+    }
+
+    let changed: Vec<usize> = (0..lines.len()).filter(|&n| lines[n].0 != ' ').collect();
+    let near_change = |n: usize| changed.iter().any(|&c| c.abs_diff(n) <= 2);
+    let mut shown = Vec::new();
+    let mut skipped = false;
+    for (n, (sign, line)) in lines.iter().enumerate() {
+        if near_change(n) {
+            if skipped && !shown.is_empty() {
+                shown.push(String::from("@@"));
+            }
+            shown.push(format!("{}{}", sign, line));
+            skipped = false;
+        } else {
+            skipped = true;
+        }
+    }
+    shown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_staticlib_to_the_lib_crate_types() {
+        let manifest = "[package]\nname = \"kernel\"\n";
+        assert!(with_staticlib(manifest)
+            .unwrap()
+            .ends_with("[lib]\ncrate-type = [\"staticlib\"]\n"));
+
+        let manifest = "[package]\nname = \"kernel\"\n\n[lib]\ncrate-type = [\"rlib\"]\n";
+        assert!(with_staticlib(manifest)
+            .unwrap()
+            .contains("crate-type = [\"rlib\", \"staticlib\"]"));
+
+        let manifest = "[lib]\ncrate-type = [\"staticlib\"]\n";
+        assert_eq!(with_staticlib(manifest).unwrap(), manifest);
+    }
+
+    #[test]
+    fn adds_the_obj_emit_to_rustflags() {
+        assert_eq!(
+            with_obj_emit("").unwrap(),
+            "[build]\nrustflags = [\"--emit=obj,link\"]\n"
+        );
+        assert_eq!(
+            with_obj_emit("[build]\nrustflags = \"-C force-frame-pointers\"\n").unwrap(),
+            "[build]\nrustflags = \"-C force-frame-pointers --emit=obj,link\"\n"
+        );
+        let config = "[build]\nrustflags = [\"--emit=obj,link\"]\n\n\
+                      [target.x86_64-os]\nrustflags = [\"-g\"]\n";
+        assert!(with_obj_emit(config)
+            .unwrap()
+            .ends_with("rustflags = [\"-g\", \"--emit=obj,link\"]\n"));
+        let config = "[build]\nrustflags = [\"--emit=obj,link\"]\n";
+        assert_eq!(with_obj_emit(config).unwrap(), config);
+    }
+}
@@ -0,0 +1,45 @@
+//! osc links kernels cargo built for bare-metal targets into bootable GRUB ISOs
+//! and boots them in an emulator.
+//!
+//! ```no_run
+//! use osc::{ImageBuilder, Project, Runner};
+//!
+//! let current_dir = std::env::current_dir().unwrap();
+//! let project = Project::discover(&current_dir, None).unwrap();
+//! let build = ImageBuilder::new(&project, "target/x86_64-os/debug/kernel")
+//!     .build()
+//!     .expect("cannot build the image");
+//! let code = Runner::new(&project, &build).run(&mut std::io::stdout());
+//! ```
+
+mod backend;
+mod build;
+mod cargo_config;
+mod clean;
+mod config;
+mod depinfo;
+mod disk;
+mod doctor;
+mod emulator;
+mod init;
+mod machine;
+mod profile;
+mod project;
+mod qmp;
+mod runner;
+mod symbolize;
+mod target;
+mod test;
+
+#[doc(hidden)]
+pub mod cli;
+
+pub use build::{BuildError, ImageBuilder, KernelBuild};
+pub use config::{OscConfig, RunProfile};
+pub use disk::DataDisk;
+pub use machine::{Drive, MachineConfig, NetworkDevice};
+pub use profile::CargoProfiles;
+pub use project::Project;
+pub use qmp::{Hook, QmpConfig};
+pub use runner::Runner;
+pub use target::Target;
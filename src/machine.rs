@@ -0,0 +1,354 @@
+use toml::Value;
+
+/// Typed QEMU machine settings, read from `[package.metadata.osc]` and from each
+/// run profile, and turned into QEMU arguments by the QEMU backend.
+#[derive(Debug, Clone, Default)]
+pub struct MachineConfig {
+    /// `-m`; an integer is taken as megabytes.
+    pub memory: Option<String>,
+    pub smp: Option<u32>,
+    pub machine: Option<String>,
+    pub cpu: Option<String>,
+    /// `none`, `gtk`, `sdl`, `vnc` (display `:0`) or `vnc=<display>`.
+    pub display: Option<String>,
+    pub devices: Vec<String>,
+    pub network: Vec<NetworkDevice>,
+    pub drives: Vec<Drive>,
+    /// `-d` log items such as `int` or `cpu_reset`.
+    pub debug: Vec<String>,
+    /// `-D` file the `debug` items are written to.
+    pub debug_log: Option<String>,
+    /// Raw arguments after the generated ones.
+    pub args: Vec<String>,
+}
+
+/// A guest network card and the host backend it is connected to.
+#[derive(Debug, Clone)]
+pub struct NetworkDevice {
+    pub model: String,
+    /// QEMU netdev type, `user` unless set.
+    pub backend: String,
+    /// Extra netdev options such as `hostfwd=tcp::5555-:22`.
+    pub options: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Drive {
+    pub file: String,
+    pub format: String,
+    /// `ide`, `ahci`, `virtio`, `scsi` or `floppy`.
+    pub interface: String,
+    pub readonly: bool,
+}
+
+impl MachineConfig {
+    pub fn from_metadata(table: &Value) -> Result<Self, String> {
+        let string = |key: &str| table.get(key).and_then(Value::as_str).map(String::from);
+        let strings = |key: &str| -> Vec<String> {
+            table
+                .get(key)
+                .and_then(Value::as_array)
+                .map(|array| {
+                    array
+                        .iter()
+                        .filter_map(|value| value.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        let memory = match table.get("memory") {
+            Some(Value::Integer(megabytes)) => Some(format!("{}M", megabytes)),
+            Some(Value::String(memory)) => Some(memory.clone()),
+            Some(_) => return Err(String::from("`memory` must be megabytes or a size string")),
+            None => None,
+        };
+        let display = string("display");
+        if let Some(display) = &display {
+            if !["none", "gtk", "sdl", "vnc"].contains(&display.as_str())
+                && !display.starts_with("vnc=")
+            {
+                return Err(format!(
+                    "`display = \"{}\"` is not one of none, gtk, sdl or vnc",
+                    display
+                ));
+            }
+        }
+
+        let mut network = Vec::new();
+        for device in table
+            .get("network")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            network.push(match device {
+                Value::String(model) => NetworkDevice {
+                    model: model.clone(),
+                    backend: String::from("user"),
+                    options: None,
+                },
+                Value::Table(_) => NetworkDevice {
+                    model: device
+                        .get("model")
+                        .and_then(Value::as_str)
+                        .ok_or("network devices need a `model`")?
+                        .to_string(),
+                    backend: device
+                        .get("backend")
+                        .and_then(Value::as_str)
+                        .unwrap_or("user")
+                        .to_string(),
+                    options: device
+                        .get("options")
+                        .and_then(Value::as_str)
+                        .map(String::from),
+                },
+                _ => return Err(String::from("network devices must be a model or a table")),
+            });
+        }
+
+        let mut drives = Vec::new();
+        for drive in table
+            .get("drives")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            let interface = drive
+                .get("interface")
+                .and_then(Value::as_str)
+                .unwrap_or("ide")
+                .to_string();
+            if !["ide", "ahci", "virtio", "scsi", "floppy"].contains(&interface.as_str()) {
+                return Err(format!("unknown drive interface `{}`", interface));
+            }
+            drives.push(Drive {
+                file: drive
+                    .get("file")
+                    .and_then(Value::as_str)
+                    .ok_or("drives need a `file`")?
+                    .to_string(),
+                format: drive
+                    .get("format")
+                    .and_then(Value::as_str)
+                    .unwrap_or("raw")
+                    .to_string(),
+                interface,
+                readonly: drive
+                    .get("readonly")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false),
+            });
+        }
+
+        let smp = match table.get("smp").and_then(Value::as_integer) {
+            Some(smp) => Some(
+                u32::try_from(smp)
+                    .ok()
+                    .filter(|smp| *smp > 0)
+                    .ok_or_else(|| format!("`smp = {}` must be a positive number of CPUs", smp))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            memory,
+            smp,
+            machine: string("machine"),
+            cpu: string("cpu"),
+            display,
+            devices: strings("devices"),
+            network,
+            drives,
+            debug: strings("debug"),
+            debug_log: string("debug-log"),
+            args: strings("args"),
+        })
+    }
+
+    /// These settings laid over `base`; lists are appended.
+    pub fn overlay(&self, base: &MachineConfig) -> MachineConfig {
+        MachineConfig {
+            memory: self.memory.clone().or_else(|| base.memory.clone()),
+            smp: self.smp.or(base.smp),
+            machine: self.machine.clone().or_else(|| base.machine.clone()),
+            cpu: self.cpu.clone().or_else(|| base.cpu.clone()),
+            display: self.display.clone().or_else(|| base.display.clone()),
+            devices: [base.devices.clone(), self.devices.clone()].concat(),
+            network: [base.network.clone(), self.network.clone()].concat(),
+            drives: [base.drives.clone(), self.drives.clone()].concat(),
+            debug: [base.debug.clone(), self.debug.clone()].concat(),
+            debug_log: self.debug_log.clone().or_else(|| base.debug_log.clone()),
+            args: [base.args.clone(), self.args.clone()].concat(),
+        }
+    }
+
+    /// QEMU arguments for these settings.
+    pub fn qemu_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = Vec::new();
+        let mut push = |flag: &str, value: String| {
+            args.push(String::from(flag));
+            args.push(value);
+        };
+        if let Some(memory) = &self.memory {
+            push("-m", memory.clone());
+        }
+        if let Some(smp) = self.smp {
+            push("-smp", smp.to_string());
+        }
+        if let Some(machine) = &self.machine {
+            push("-machine", machine.clone());
+        }
+        if let Some(cpu) = &self.cpu {
+            push("-cpu", cpu.clone());
+        }
+        match self.display.as_deref() {
+            Some("vnc") => push("-display", String::from("vnc=:0")),
+            Some(display) => push("-display", String::from(display)),
+            None => {}
+        }
+        for device in &self.devices {
+            push("-device", device.clone());
+        }
+        for (index, device) in self.network.iter().enumerate() {
+            let mut netdev = format!("{},id=net{}", device.backend, index);
+            if let Some(options) = &device.options {
+                netdev.push(',');
+                netdev.push_str(options);
+            }
+            push("-netdev", netdev);
+            push("-device", format!("{},netdev=net{}", device.model, index));
+        }
+        let mut ahci_port = 0;
+        for (index, drive) in self.drives.iter().enumerate() {
+            let readonly = if drive.readonly { ",readonly=on" } else { "" };
+            if drive.interface == "ahci" {
+                if ahci_port == 0 {
+                    push("-device", String::from("ahci,id=ahci0"));
+                }
+                push(
+                    "-drive",
+                    format!(
+                        "id=disk{},file={},format={},if=none{}",
+                        index, drive.file, drive.format, readonly
+                    ),
+                );
+                push(
+                    "-device",
+                    format!("ide-hd,drive=disk{},bus=ahci0.{}", index, ahci_port),
+                );
+                ahci_port += 1;
+            } else {
+                push(
+                    "-drive",
+                    format!(
+                        "file={},format={},if={}{}",
+                        drive.file, drive.format, drive.interface, readonly
+                    ),
+                );
+            }
+        }
+        if !self.debug.is_empty() {
+            push("-d", self.debug.join(","));
+        }
+        if let Some(debug_log) = &self.debug_log {
+            push("-D", debug_log.clone());
+        }
+        args.extend(self.args.iter().cloned());
+        args
+    }
+}
+
+/// Problems in a complete QEMU argument list that QEMU would either reject or
+/// silently resolve in a surprising way.
+pub fn conflicts(args: &[String]) -> Vec<String> {
+    let mut conflicts = Vec::new();
+    let values = |flags: &[&str]| -> Vec<&str> {
+        args.windows(2)
+            .filter(|pair| flags.contains(&pair[0].as_str()))
+            .map(|pair| pair[1].as_str())
+            .collect()
+    };
+    let has = |flag: &str| args.iter().any(|arg| arg == flag);
+
+    if has("-nographic") && !values(&["-display"]).is_empty() {
+        conflicts.push(String::from(
+            "`-nographic` and `-display` both choose the display, use only one",
+        ));
+    }
+    let serials = values(&["-serial"]);
+    for (index, serial) in serials.iter().enumerate() {
+        if serials[..index].contains(serial) {
+            conflicts.push(format!("`-serial {}` is given more than once", serial));
+        }
+    }
+    if has("-nographic") && serials.iter().any(|serial| serial.contains("stdio")) {
+        conflicts.push(String::from(
+            "`-nographic` already puts the serial port on stdio, drop `-serial stdio`",
+        ));
+    }
+    for flags in [
+        &["-m"][..],
+        &["-smp"][..],
+        &["-machine", "-M"][..],
+        &["-cpu"][..],
+        &["-display"][..],
+        &["-D"][..],
+    ] {
+        let mut given = values(flags);
+        given.sort_unstable();
+        given.dedup();
+        if given.len() > 1 {
+            conflicts.push(format!(
+                "`{}` is given with different values: {}",
+                flags[0],
+                given.join(", ")
+            ));
+        }
+    }
+    conflicts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn accepts_consistent_arguments() {
+        assert!(conflicts(&args("-m 256M -smp 2 -serial stdio -display none -m 256M")).is_empty());
+        assert!(conflicts(&args("-serial stdio -serial file:com2.log")).is_empty());
+    }
+
+    #[test]
+    fn reports_flags_given_with_different_values() {
+        assert_eq!(
+            conflicts(&args("-m 256M -smp 2 -m 1G")),
+            ["`-m` is given with different values: 1G, 256M"]
+        );
+        assert_eq!(
+            conflicts(&args("-machine q35 -M pc")),
+            ["`-machine` is given with different values: pc, q35"]
+        );
+    }
+
+    #[test]
+    fn reports_display_and_serial_clashes() {
+        assert_eq!(
+            conflicts(&args("-nographic -display none")),
+            ["`-nographic` and `-display` both choose the display, use only one"]
+        );
+        assert_eq!(
+            conflicts(&args("-serial stdio -serial stdio")),
+            ["`-serial stdio` is given more than once"]
+        );
+        assert_eq!(
+            conflicts(&args("-nographic -serial mon:stdio")),
+            ["`-nographic` already puts the serial port on stdio, drop `-serial stdio`"]
+        );
+    }
+}
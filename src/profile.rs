@@ -0,0 +1,118 @@
+use std::path::Path;
+
+use toml::map::Map;
+use toml::Value;
+
+/// Cargo's `[profile.<name>]` tables from the workspace root manifest, the only
+/// place cargo reads them from.
+#[derive(Debug, Clone, Default)]
+pub struct CargoProfiles {
+    profiles: Map<String, Value>,
+}
+
+impl CargoProfiles {
+    pub fn from_manifest(manifest: &Value) -> Self {
+        CargoProfiles {
+            profiles: manifest
+                .get("profile")
+                .and_then(Value::as_table)
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// `name` followed by every profile it inherits from, nearest first. `test`
+    /// and `bench` inherit `dev` and `release` unless told otherwise.
+    pub fn chain(&self, name: &str) -> Vec<String> {
+        let mut chain = vec![name.to_string()];
+        loop {
+            let current = chain.last().unwrap().as_str();
+            let parent = match self
+                .profiles
+                .get(current)
+                .and_then(|profile| profile.get("inherits"))
+                .and_then(Value::as_str)
+            {
+                Some(parent) => parent,
+                None => match current {
+                    "test" => "dev",
+                    "bench" => "release",
+                    _ => break,
+                },
+            };
+            // Cargo rejects cycles itself; stop rather than loop.
+            if chain.iter().any(|name| name == parent) {
+                break;
+            }
+            chain.push(parent.to_string());
+        }
+        chain
+    }
+
+    /// Whether the profile builds with debug info, which osc passes on to nasm.
+    pub fn debuginfo(&self, name: &str) -> bool {
+        for profile in self.chain(name) {
+            let debug = self
+                .profiles
+                .get(&profile)
+                .and_then(|profile| profile.get("debug"));
+            match debug {
+                Some(Value::Boolean(debug)) => return *debug,
+                Some(Value::Integer(level)) => return *level > 0,
+                Some(Value::String(level)) => return level != "none",
+                _ => {}
+            }
+            match profile.as_str() {
+                "dev" => return true,
+                "release" => return false,
+                _ => {}
+            }
+        }
+        false
+    }
+}
+
+/// The profile cargo builds with for `args`: `--release`, `--profile <name>` or
+/// `default` when neither is given.
+pub fn from_args(args: &[String], default: &str) -> String {
+    let mut profile = String::from(default);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--release" => profile = String::from("release"),
+            "--profile" => {
+                if let Some(name) = args.next() {
+                    profile = name.clone();
+                }
+            }
+            _ => {
+                if let Some(name) = arg.strip_prefix("--profile=") {
+                    profile = String::from(name);
+                }
+            }
+        }
+    }
+    profile
+}
+
+/// Cargo keeps the `dev` and `test` profiles in `debug/` and `bench` in `release/`.
+pub fn dir_name(profile: &str) -> &str {
+    match profile {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        profile => profile,
+    }
+}
+
+/// The profile an artifact in `target/<triple>/<profile dir>/` or its `deps/`
+/// was built with. Artifacts in `debug/` are taken as `dev`.
+pub fn of_artifact(artifact: &Path) -> Option<&str> {
+    let mut dir = artifact.parent()?;
+    if dir.file_name()? == "deps" {
+        dir = dir.parent()?;
+    }
+    match dir.file_name()?.to_str()? {
+        "debug" => Some("dev"),
+        profile => Some(profile),
+    }
+}
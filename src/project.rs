@@ -0,0 +1,493 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use toml::Value;
+
+use crate::cargo_config::CargoConfig;
+use crate::config::{apply_settings, merge_metadata, OscConfig};
+use crate::depinfo::DepInfo;
+use crate::profile::{self, CargoProfiles};
+use crate::target::{self, Target};
+
+/// The kernel package osc is working on, found the way cargo finds packages:
+/// the nearest `Cargo.toml` upwards from the current directory, and the workspace
+/// that contains it.
+#[derive(Debug, Clone)]
+pub struct Project {
+    /// Directory of the kernel package; holds `linker.ld`, `src/boot` and `iso/`.
+    pub kernel_dir: PathBuf,
+    /// Root of the workspace, or `kernel_dir` for a standalone package.
+    pub workspace_root: PathBuf,
+    pub crate_name: String,
+    /// Crate root of the lib target, `[lib] path` or `src/lib.rs`.
+    pub lib_path: PathBuf,
+    /// From `CARGO_BUILD_TARGET` or `build.target` in cargo's config;
+    /// `--target` replaces it through [`Project::for_cargo_args`].
+    pub target: Option<Target>,
+    /// Cargo's target directory: `CARGO_TARGET_DIR`, `build.target-dir` or
+    /// `target/` in the workspace root.
+    pub target_root: PathBuf,
+    pub osc_config: OscConfig,
+    /// `[profile.*]` from the workspace root manifest.
+    pub cargo_profiles: CargoProfiles,
+}
+
+impl Project {
+    /// Finds the kernel package starting from `current_dir`. In a workspace,
+    /// `package` picks the member (as `-p` does for cargo); without it osc falls back
+    /// to the package cargo is running (`CARGO_MANIFEST_DIR`), the member the current
+    /// directory is in, and finally the only member with `[package.metadata.osc]`.
+    pub fn discover(current_dir: &Path, package: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        Self::discover_with_settings(current_dir, package, &[])
+    }
+
+    /// [`Project::discover`] with `settings`, such as `OSC_*` variables followed by
+    /// `--config`, laid over the osc metadata; see [`apply_settings`].
+    pub fn discover_with_settings(
+        current_dir: &Path,
+        package: Option<&str>,
+        settings: &[(String, Value)],
+    ) -> Result<Self, Box<dyn Error>> {
+        let manifest_dir = current_dir
+            .ancestors()
+            .find(|dir| dir.join("Cargo.toml").is_file())
+            .ok_or("could not find Cargo.toml in this directory or any parent directory")?;
+        let manifest = read_toml(&manifest_dir.join("Cargo.toml"))?;
+
+        let workspace_override = manifest
+            .get("package")
+            .and_then(|package| package.get("workspace"))
+            .and_then(Value::as_str);
+        let (workspace_root, workspace) = if manifest.get("workspace").is_some() {
+            (manifest_dir.to_path_buf(), Some(manifest.clone()))
+        } else if let Some(root) = workspace_override {
+            let root = manifest_dir.join(root);
+            let toml = read_toml(&root.join("Cargo.toml"))?;
+            (root, Some(toml))
+        } else {
+            let parent = manifest_dir
+                .ancestors()
+                .skip(1)
+                .filter(|dir| dir.join("Cargo.toml").is_file())
+                .find_map(|dir| {
+                    let toml = read_toml(&dir.join("Cargo.toml")).ok()?;
+                    toml.get("workspace")?;
+                    Some((dir.to_path_buf(), toml))
+                });
+            // Like cargo, a package the nearest workspace does not list as a member,
+            // or excludes, is its own workspace.
+            match parent {
+                Some((root, toml))
+                    if workspace_members(&root, &toml)?
+                        .iter()
+                        .any(|member| member == manifest_dir) =>
+                {
+                    (root, Some(toml))
+                }
+                _ => (manifest_dir.to_path_buf(), None),
+            }
+        };
+
+        let kernel_dir = match &workspace {
+            Some(workspace) => {
+                let members = workspace_members(&workspace_root, workspace)?;
+                select_member(&members, manifest_dir, &manifest, package)?
+            }
+            None => manifest_dir.to_path_buf(),
+        };
+        let kernel_manifest = if kernel_dir == manifest_dir {
+            manifest.clone()
+        } else {
+            read_toml(&kernel_dir.join("Cargo.toml"))?
+        };
+
+        let package_table = kernel_manifest
+            .get("package")
+            .ok_or_else(|| format!("{} has no [package]", kernel_dir.display()))?;
+        let crate_name = package_table
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or("package has no name")?
+            .to_string();
+        let lib_path = kernel_dir.join(
+            kernel_manifest
+                .get("lib")
+                .and_then(|lib| lib.get("path"))
+                .and_then(Value::as_str)
+                .unwrap_or("src/lib.rs"),
+        );
+
+        let defaults = workspace
+            .as_ref()
+            .and_then(|workspace| workspace.get("workspace"))
+            .and_then(|workspace| workspace.get("metadata"))
+            .and_then(|metadata| metadata.get("osc"));
+        let overrides = package_table
+            .get("metadata")
+            .and_then(|metadata| metadata.get("osc"));
+        let metadata = match (defaults, overrides) {
+            (Some(defaults), Some(overrides)) => merge_metadata(defaults, overrides),
+            (Some(osc), None) | (None, Some(osc)) => osc.clone(),
+            (None, None) => Value::Table(Default::default()),
+        };
+        let osc_config = OscConfig::from_metadata(&apply_settings(&metadata, settings))?;
+
+        let cargo_profiles = CargoProfiles::from_manifest(workspace.as_ref().unwrap_or(&manifest));
+
+        let cargo_config = CargoConfig::discover(current_dir)?;
+        let config_target = cargo_config
+            .get(&["build", "target"])
+            .and_then(|(target, root)| {
+                // `build.target` may list several targets; the kernel is built for the first.
+                let target = target.as_str().or_else(|| target.get(0)?.as_str())?;
+                Some(Target::parse(target, root))
+            });
+        let target = match env::var("CARGO_BUILD_TARGET") {
+            Ok(target) => Some(Target::parse(&target, current_dir)),
+            Err(_) => config_target,
+        };
+        let target_root = match env::var_os("CARGO_TARGET_DIR")
+            .or_else(|| env::var_os("CARGO_BUILD_TARGET_DIR"))
+        {
+            Some(target_dir) => current_dir.join(target_dir),
+            None => match cargo_config.get(&["build", "target-dir"]) {
+                Some((Value::String(target_dir), root)) => root.join(target_dir),
+                _ => workspace_root.join("target"),
+            },
+        };
+
+        Ok(Self {
+            kernel_dir,
+            workspace_root,
+            crate_name,
+            lib_path,
+            target,
+            target_root,
+            osc_config,
+            cargo_profiles,
+        })
+    }
+
+    /// The project as cargo builds it with `args`: for the `--target` given there
+    /// and with the osc settings of the cargo profile.
+    pub fn for_cargo_args(&self, args: &[String], current_dir: &Path, profile: &str) -> Self {
+        let mut project = self.for_cargo_profile(&profile::from_args(args, profile));
+        if let Some(target) = target::from_args(args) {
+            project.target = Some(Target::parse(target, current_dir));
+        }
+        project
+    }
+
+    /// The project the executable cargo hands `osc runner` was built for. Its
+    /// path names the cargo profile and, when `--target` picked another one than
+    /// the config, the target; a spec is only known by its name there.
+    pub fn for_artifact(&self, artifact: &Path) -> Self {
+        let mut project = self.for_cargo_profile(profile::of_artifact(artifact).unwrap_or("dev"));
+        let target_dir = artifact
+            .ancestors()
+            .skip(1)
+            .find(|dir| dir.file_name().is_some_and(|name| name != "deps"))
+            .and_then(Path::parent);
+        if let Some(target_dir) = target_dir {
+            let name = target_dir.file_name().and_then(|name| name.to_str());
+            if target_dir.parent() == Some(self.target_root.as_path())
+                && name != self.target.as_ref().and_then(Target::dir_name)
+            {
+                project.target = name.map(|name| Target::Triple(String::from(name)));
+            }
+        }
+        project
+    }
+
+    /// The project with the osc settings for kernels built with the cargo
+    /// `profile`, or those of the nearest profile it inherits that has any.
+    pub fn for_cargo_profile(&self, profile: &str) -> Self {
+        let mut project = self.clone();
+        if let Some(osc_config) = self
+            .cargo_profiles
+            .chain(profile)
+            .iter()
+            .find_map(|profile| self.osc_config.cargo_profiles.get(profile))
+        {
+            project.osc_config = osc_config.clone();
+        }
+        project
+    }
+
+    /// A source path from dep-info; cargo runs rustc in the workspace root, so
+    /// relative paths are relative to it.
+    pub fn source_path(&self, path: &Path) -> PathBuf {
+        self.workspace_root.join(path)
+    }
+
+    /// Whether the dep-info describes the package's lib target.
+    pub(crate) fn is_lib(&self, dep_info: &DepInfo) -> bool {
+        dep_info
+            .root_source()
+            .is_some_and(|root| self.source_path(root) == self.lib_path)
+    }
+
+    /// `<target root>/<triple or spec name>`, where cargo puts the kernel's artifacts.
+    pub fn target_dir(&self) -> Option<PathBuf> {
+        Some(self.target_root.join(self.target.as_ref()?.dir_name()?))
+    }
+}
+
+fn read_toml(path: &Path) -> Result<Value, Box<dyn Error>> {
+    let string = fs::read_to_string(path).map_err(|e| format!("{} {}", path.display(), e))?;
+    Ok(toml::de::from_str(&string)?)
+}
+
+/// Member directories listed in `[workspace]`, with globs expanded.
+fn workspace_members(root: &Path, workspace: &Value) -> Result<Vec<PathBuf>, String> {
+    let section = workspace.get("workspace");
+    let list = |key: &str| -> Vec<String> {
+        section
+            .and_then(|section| section.get(key))
+            .and_then(Value::as_array)
+            .map(|array| {
+                array
+                    .iter()
+                    .filter_map(|value| value.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let excluded: Vec<PathBuf> = list("exclude").iter().map(|dir| root.join(dir)).collect();
+
+    let mut members = Vec::new();
+    if workspace.get("package").is_some() {
+        members.push(root.to_path_buf());
+    }
+    for member in list("members") {
+        if member.contains(['*', '?', '[']) {
+            let found = expand_glob(root, &member)?;
+            members.extend(
+                found
+                    .into_iter()
+                    .filter(|dir| dir.join("Cargo.toml").is_file()),
+            );
+        } else {
+            members.push(root.join(member));
+        }
+    }
+    members.retain(|member| !excluded.contains(member));
+    Ok(members)
+}
+
+/// The directories under `root` matching `pattern`, a path whose components may
+/// use the `*`, `?` and `[...]` globs cargo accepts in `members`.
+fn expand_glob(root: &Path, pattern: &str) -> Result<Vec<PathBuf>, String> {
+    if pattern.contains("**") {
+        return Err(format!(
+            "unsupported workspace members pattern `{}`, list the members or use `*`",
+            pattern
+        ));
+    }
+    let mut dirs = vec![root.to_path_buf()];
+    for component in pattern.split('/').filter(|component| !component.is_empty()) {
+        if !component.contains(['*', '?', '[']) {
+            dirs.iter_mut().for_each(|dir| dir.push(component));
+            continue;
+        }
+        let component: Vec<char> = component.chars().collect();
+        let mut matched = Vec::new();
+        for dir in &dirs {
+            for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+                let name: Vec<char> = entry.file_name().to_string_lossy().chars().collect();
+                if glob_match(&component, &name) && entry.path().is_dir() {
+                    matched.push(entry.path());
+                }
+            }
+        }
+        matched.sort();
+        dirs = matched;
+    }
+    Ok(dirs)
+}
+
+/// Whether `name` matches one glob path component.
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern {
+        [] => name.is_empty(),
+        ['*', rest @ ..] => (0..=name.len()).any(|skip| glob_match(rest, &name[skip..])),
+        ['?', rest @ ..] => !name.is_empty() && glob_match(rest, &name[1..]),
+        ['[', rest @ ..] => {
+            // A `]` right after `[` or `[!` is part of the class.
+            let negated = rest.first() == Some(&'!');
+            let start = usize::from(negated);
+            let close = rest
+                .get(start + 1..)
+                .and_then(|after| after.iter().position(|char| *char == ']'));
+            let Some(close) = close else {
+                return name.first() == Some(&'[')
+                    && glob_match(rest, name.get(1..).unwrap_or_default());
+            };
+            let end = start + 1 + close;
+            let class = &rest[start..end];
+            let Some(first) = name.first() else {
+                return false;
+            };
+            let mut in_class = false;
+            let mut index = 0;
+            while index < class.len() {
+                if index + 2 < class.len() && class[index + 1] == '-' {
+                    in_class |= (class[index]..=class[index + 2]).contains(first);
+                    index += 3;
+                } else {
+                    in_class |= class[index] == *first;
+                    index += 1;
+                }
+            }
+            in_class != negated && glob_match(&rest[end + 1..], &name[1..])
+        }
+        [char, rest @ ..] => name.first() == Some(char) && glob_match(rest, &name[1..]),
+    }
+}
+
+fn select_member(
+    members: &[PathBuf],
+    manifest_dir: &Path,
+    manifest: &Value,
+    package: Option<&str>,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let package_name = |dir: &Path| -> Option<String> {
+        let toml = read_toml(&dir.join("Cargo.toml")).ok()?;
+        Some(toml.get("package")?.get("name")?.as_str()?.to_string())
+    };
+
+    if let Some(package) = package {
+        return members
+            .iter()
+            .find(|member| package_name(member).as_deref() == Some(package))
+            .cloned()
+            .ok_or_else(|| {
+                format!("package `{}` is not a member of the workspace", package).into()
+            });
+    }
+    if let Ok(manifest_dir) = env::var("CARGO_MANIFEST_DIR") {
+        let manifest_dir = PathBuf::from(manifest_dir);
+        if members.contains(&manifest_dir) {
+            return Ok(manifest_dir);
+        }
+    }
+    if manifest.get("package").is_some() {
+        return Ok(manifest_dir.to_path_buf());
+    }
+
+    let kernels: Vec<&PathBuf> = members
+        .iter()
+        .filter(|member| {
+            read_toml(&member.join("Cargo.toml"))
+                .ok()
+                .and_then(|toml| toml.get("package")?.get("metadata")?.get("osc").cloned())
+                .is_some()
+        })
+        .collect();
+    match kernels.as_slice() {
+        [kernel] => Ok(kernel.to_path_buf()),
+        [] => {
+            Err("no workspace member has [package.metadata.osc], select the kernel with -p".into())
+        }
+        _ => Err(
+            "several workspace members have [package.metadata.osc], select the kernel with -p"
+                .into(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, text: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, text).unwrap();
+    }
+
+    fn package(name: &str) -> String {
+        format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\n", name)
+    }
+
+    #[test]
+    fn adopts_a_parent_workspace_only_for_its_members() {
+        let root = env::temp_dir().join(format!("osc-workspace-{}", std::process::id()));
+        write(
+            &root.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"kernel\", \"excluded\"]\nexclude = [\"excluded\"]\n",
+        );
+        write(&root.join("kernel/Cargo.toml"), &package("kernel"));
+        write(&root.join("excluded/Cargo.toml"), &package("excluded"));
+        write(&root.join("unlisted/Cargo.toml"), &package("unlisted"));
+        write(
+            &root.join("pointed/Cargo.toml"),
+            &(package("pointed") + "workspace = \"../other\"\n"),
+        );
+        write(
+            &root.join("other/Cargo.toml"),
+            "[workspace]\nmembers = [\"../pointed\"]\n",
+        );
+
+        let workspace_root = |dir: &str| {
+            Project::discover(&root.join(dir), None)
+                .unwrap()
+                .workspace_root
+        };
+        assert_eq!(workspace_root("kernel"), root);
+        assert_eq!(workspace_root("excluded"), root.join("excluded"));
+        assert_eq!(workspace_root("unlisted"), root.join("unlisted"));
+        assert_eq!(workspace_root("pointed"), root.join("pointed/../other"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn matches_glob_components() {
+        let matches = |pattern: &str, name: &str| {
+            let pattern: Vec<char> = pattern.chars().collect();
+            let name: Vec<char> = name.chars().collect();
+            glob_match(&pattern, &name)
+        };
+        assert!(matches("*", "kernel"));
+        assert!(matches("kernel-*", "kernel-x86"));
+        assert!(!matches("kernel-*", "kernel"));
+        assert!(matches("k?rnel", "kernel"));
+        assert!(matches("[a-k]ernel", "kernel"));
+        assert!(!matches("[!k]ernel", "kernel"));
+        assert!(matches("[]", "[]"));
+        assert!(!matches("[!]", "a"));
+    }
+
+    #[test]
+    fn expands_glob_members() {
+        let root = env::temp_dir().join(format!("osc-globs-{}", std::process::id()));
+        write(
+            &root.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"crates/kernel-*\", \"t?ols/*\"]\n",
+        );
+        write(&root.join("crates/kernel-x86/Cargo.toml"), &package("x86"));
+        write(&root.join("crates/kernel-arm/Cargo.toml"), &package("arm"));
+        write(&root.join("crates/other/Cargo.toml"), &package("other"));
+        write(&root.join("tools/xtask/Cargo.toml"), &package("xtask"));
+        let workspace = read_toml(&root.join("Cargo.toml")).unwrap();
+        assert_eq!(
+            workspace_members(&root, &workspace).unwrap(),
+            [
+                root.join("crates/kernel-arm"),
+                root.join("crates/kernel-x86"),
+                root.join("tools/xtask"),
+            ]
+        );
+        let project = Project::discover(&root, Some("x86")).unwrap();
+        assert_eq!(project.kernel_dir, root.join("crates/kernel-x86"));
+
+        let workspace: Value = toml::from_str("[workspace]\nmembers = [\"crates/**\"]").unwrap();
+        assert!(workspace_members(&root, &workspace)
+            .unwrap_err()
+            .contains("unsupported workspace members pattern"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,289 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value as Json};
+use toml::Value;
+
+/// Something osc asks QEMU for when a run times out or the guest panics.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hook {
+    /// `info registers`, written into the run's output.
+    Registers,
+    /// The screen, saved as a PPM file next to `kernel.sym`.
+    Screenshot,
+    /// The `memory` range of guest physical memory, saved next to `kernel.sym`.
+    Memory,
+}
+
+/// Settings from `[package.metadata.osc.qmp]`.
+#[derive(Debug, Clone, Default)]
+pub struct QmpConfig {
+    pub on_timeout: Vec<Hook>,
+    /// Run when the guest reports a panic through the pvpanic device.
+    pub on_panic: Vec<Hook>,
+    /// Physical address and length dumped by the `memory` hook.
+    pub memory: (u64, u64),
+    /// Keys typed once the guest has run for `keys-delay` seconds, such as `ret`
+    /// or `ctrl-alt-delete`.
+    pub send_keys: Vec<String>,
+    pub keys_delay: u64,
+}
+
+impl QmpConfig {
+    pub fn from_metadata(qmp: &Value) -> Result<Self, String> {
+        let hooks = |key: &str| -> Result<Vec<Hook>, String> {
+            let mut hooks = Vec::new();
+            for hook in qmp.get(key).and_then(Value::as_array).into_iter().flatten() {
+                hooks.push(match hook.as_str() {
+                    Some("registers") => Hook::Registers,
+                    Some("screenshot") => Hook::Screenshot,
+                    Some("memory") => Hook::Memory,
+                    _ => {
+                        return Err(format!(
+                            "`{}` hooks are registers, screenshot or memory, not {}",
+                            key, hook
+                        ))
+                    }
+                });
+            }
+            Ok(hooks)
+        };
+        let integer = |table: &Value, key: &str| -> Result<Option<u64>, String> {
+            match table.get(key).and_then(Value::as_integer) {
+                Some(value) => u64::try_from(value)
+                    .map(Some)
+                    .map_err(|_| format!("`qmp` `{}` must not be negative", key)),
+                None => Ok(None),
+            }
+        };
+
+        let memory = match qmp.get("memory") {
+            Some(memory) => (
+                integer(memory, "address")?.ok_or("`qmp.memory` needs an `address`")?,
+                integer(memory, "size")?.ok_or("`qmp.memory` needs a `size`")?,
+            ),
+            // The first megabyte above the BIOS area, where the kernel is loaded.
+            None => (0x100000, 0x100000),
+        };
+        Ok(Self {
+            on_timeout: hooks("on-timeout")?,
+            on_panic: hooks("on-panic")?,
+            memory,
+            send_keys: qmp
+                .get("send-keys")
+                .and_then(Value::as_array)
+                .map(|keys| {
+                    keys.iter()
+                        .filter_map(|key| key.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            keys_delay: integer(qmp, "keys-delay")?.unwrap_or(1),
+        })
+    }
+}
+
+static RUN: AtomicUsize = AtomicUsize::new(0);
+
+/// The QMP socket of one QEMU run and where its hooks save files.
+#[derive(Debug)]
+pub struct Monitor {
+    pub socket: PathBuf,
+    pub dump_dir: PathBuf,
+    pub config: QmpConfig,
+    run: usize,
+}
+
+impl Monitor {
+    pub fn new(config: QmpConfig, dump_dir: &Path) -> Self {
+        let run = RUN.fetch_add(1, Ordering::SeqCst);
+        // Socket paths are limited to about 100 bytes, too short for target dirs.
+        let socket = env::temp_dir().join(format!("osc-qmp-{}-{}.sock", process::id(), run));
+        Monitor {
+            socket,
+            dump_dir: dump_dir.to_path_buf(),
+            config,
+            run,
+        }
+    }
+
+    /// QEMU arguments opening the socket, and the pvpanic device when there are
+    /// panic hooks. `arch` picks the ISA or PCI flavour of the device.
+    pub fn qemu_args(&self, arch: &str) -> Vec<String> {
+        let mut args = vec![
+            String::from("-qmp"),
+            format!("unix:{},server=on,wait=off", self.socket.display()),
+        ];
+        if !self.config.on_panic.is_empty() {
+            let pvpanic = match arch {
+                "x86_64" | "x86" | "i386" | "i586" | "i686" => "pvpanic",
+                _ => "pvpanic-pci",
+            };
+            args.extend(
+                ["-device", pvpanic, "-action", "panic=pause"]
+                    .iter()
+                    .map(|arg| arg.to_string()),
+            );
+        }
+        args
+    }
+
+    /// Runs `hooks`, returning what they printed or saved for the run's output.
+    pub fn run_hooks(&self, qmp: &mut Qmp, hooks: &[Hook]) -> Vec<String> {
+        let mut notes = Vec::new();
+        for hook in hooks {
+            let result = match hook {
+                Hook::Registers => qmp
+                    .human("info registers")
+                    .map(|registers| format!("---- info registers ----\n{}", registers)),
+                Hook::Screenshot => {
+                    let file = self.dump_file("screenshot", "ppm");
+                    qmp.execute("screendump", json!({ "filename": file }))
+                        .map(|_| format!("Screenshot saved to {}", file.display()))
+                }
+                Hook::Memory => {
+                    let (address, size) = self.config.memory;
+                    let file = self.dump_file("memory", "bin");
+                    qmp.execute(
+                        "pmemsave",
+                        json!({ "val": address, "size": size, "filename": file }),
+                    )
+                    .map(|_| {
+                        format!(
+                            "Memory {:#x}..{:#x} saved to {}",
+                            address,
+                            address + size,
+                            file.display()
+                        )
+                    })
+                }
+            };
+            notes.push(result.unwrap_or_else(|e| format!("QMP {:?} hook failed {}", hook, e)));
+        }
+        notes
+    }
+
+    /// A file in the dump directory that is unique to this run.
+    pub fn dump_file(&self, name: &str, extension: &str) -> PathBuf {
+        self.dump_dir.join(format!(
+            "{}-{}-{}.{}",
+            name,
+            process::id(),
+            self.run,
+            extension
+        ))
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        fs::remove_file(&self.socket).ok();
+    }
+}
+
+/// A QMP connection with capabilities negotiated.
+pub struct Qmp {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    /// Partial line kept across read timeouts.
+    line: String,
+    events: Vec<Json>,
+}
+
+impl Qmp {
+    pub fn connect(socket: &Path) -> io::Result<Self> {
+        let writer = UnixStream::connect(socket)?;
+        writer.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let mut qmp = Qmp {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+            line: String::new(),
+            events: Vec::new(),
+        };
+        // Greeting.
+        qmp.message(Instant::now() + Duration::from_secs(5))?;
+        qmp.execute("qmp_capabilities", json!({}))?;
+        Ok(qmp)
+    }
+
+    /// The next complete message, failing with `WouldBlock` or `TimedOut` if none
+    /// arrived before `deadline`.
+    fn message(&mut self, deadline: Instant) -> io::Result<Json> {
+        loop {
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "QMP closed")),
+                Ok(_) if self.line.ends_with('\n') => {
+                    let message = serde_json::from_str(&self.line);
+                    self.line.clear();
+                    return message.map_err(io::Error::from);
+                }
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if Instant::now() >= deadline {
+                        return Err(e);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn execute(&mut self, command: &str, arguments: Json) -> io::Result<Json> {
+        let request = json!({ "execute": command, "arguments": arguments });
+        writeln!(self.writer, "{}", request)?;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let message = self.message(deadline)?;
+            if message.get("event").is_some() {
+                self.events.push(message);
+            } else if let Some(error) = message.get("error") {
+                return Err(io::Error::other(error.to_string()));
+            } else if let Some(result) = message.get("return") {
+                return Ok(result.clone());
+            }
+        }
+    }
+
+    /// Runs a human monitor command such as `info registers`.
+    pub fn human(&mut self, command_line: &str) -> io::Result<String> {
+        let result = self.execute(
+            "human-monitor-command",
+            json!({ "command-line": command_line }),
+        )?;
+        Ok(result.as_str().unwrap_or_default().to_string())
+    }
+
+    /// Presses `keys` together, e.g. `ctrl-alt-delete`.
+    pub fn send_key(&mut self, keys: &str) -> io::Result<()> {
+        let keys: Vec<Json> = keys
+            .split('-')
+            .map(|key| json!({ "type": "qcode", "data": key }))
+            .collect();
+        self.execute("send-key", json!({ "keys": keys }))?;
+        Ok(())
+    }
+
+    /// The next event QEMU sent, such as `{"event": "SHUTDOWN", "data": {..}}`,
+    /// waiting up to one read timeout for it.
+    pub fn next_event(&mut self) -> io::Result<Option<Json>> {
+        if !self.events.is_empty() {
+            return Ok(Some(self.events.remove(0)));
+        }
+        match self.message(Instant::now()) {
+            Ok(message) if message.get("event").is_some() => Ok(Some(message)),
+            Ok(_) => Ok(None),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn quit(&mut self) -> io::Result<()> {
+        self.execute("quit", json!({})).map(|_| ())
+    }
+}
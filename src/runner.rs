@@ -0,0 +1,176 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::backend::{self, Boot, Outcome};
+use crate::build::KernelBuild;
+use crate::disk::FreshDisks;
+use crate::emulator;
+use crate::machine::MachineConfig;
+use crate::project::Project;
+use crate::qmp::{Monitor, QmpConfig};
+use crate::symbolize::Symbolizer;
+
+/// Boots a [`KernelBuild`] with the configured runner backend.
+pub struct Runner<'a> {
+    project: &'a Project,
+    build: &'a KernelBuild,
+    args: Vec<String>,
+    machine: MachineConfig,
+    qmp: Option<QmpConfig>,
+    test: bool,
+}
+
+impl<'a> Runner<'a> {
+    pub fn new(project: &'a Project, build: &'a KernelBuild) -> Self {
+        Runner {
+            project,
+            build,
+            args: Vec::new(),
+            machine: project.osc_config.machine.clone(),
+            qmp: project.osc_config.qmp.clone(),
+            test: false,
+        }
+    }
+
+    /// Emulator arguments after the ones generated from the machine settings,
+    /// followed by the backend's own from the osc settings (`run-args` or
+    /// `test-args` for QEMU).
+    pub fn args(mut self, args: impl IntoIterator<Item = String>) -> Self {
+        self.args.extend(args);
+        self
+    }
+
+    /// Machine settings to use instead of the project's, such as
+    /// [`OscConfig::machine`](crate::config::OscConfig::machine) with a run profile.
+    pub fn machine(mut self, machine: MachineConfig) -> Self {
+        self.machine = machine;
+        self
+    }
+
+    /// QMP hooks to use instead of `[package.metadata.osc.qmp]`.
+    pub fn qmp(mut self, qmp: QmpConfig) -> Self {
+        self.qmp = Some(qmp);
+        self
+    }
+
+    /// Boots as a test: `test-timeout` applies and triple faults are detected.
+    pub fn test(mut self, test: bool) -> Self {
+        self.test = test;
+        self
+    }
+
+    /// Boots the kernel, passing its serial output to `output`. Returns the exit
+    /// code the guest reported, or why it has none.
+    pub fn run(self, output: &mut dyn Write) -> Result<Option<i32>, String> {
+        let Runner {
+            project,
+            build,
+            mut args,
+            mut machine,
+            qmp,
+            test,
+        } = self;
+        let osc_config = &project.osc_config;
+        let backend = backend::backend(project).map_err(|e| e.to_string())?;
+        if test {
+            backend::check_tests(backend.as_ref())?;
+        }
+        args.extend(backend.config_args(osc_config, test));
+        let disks =
+            FreshDisks::copy(&build.disks).map_err(|e| format!("Cannot copy disk {}", e))?;
+        machine.drives.extend(disks.drives.iter().cloned());
+        let qemu = backend.name() == "qemu";
+        let detect_triple_faults = qemu && test && osc_config.detect_triple_faults;
+        // The QMP socket also reports guest resets under KVM, where nothing is logged.
+        let monitor = if qemu && (qmp.is_some() || detect_triple_faults) {
+            let qmp = qmp.unwrap_or_default();
+            let monitor = Monitor::new(qmp, build.symbols.parent().unwrap());
+            let arch = project
+                .target
+                .as_ref()
+                .and_then(emulator::target_arch)
+                .unwrap_or_else(|| String::from("x86_64"));
+            machine.args.extend(monitor.qemu_args(&arch));
+            Some(monitor)
+        } else {
+            None
+        };
+        let mut reset_log = None;
+        if let (true, Some(monitor)) = (detect_triple_faults, &monitor) {
+            machine.args.push(String::from("-no-reboot"));
+            // Raw `-d`/`-D` arguments would fight with generated ones.
+            if !args.iter().any(|arg| arg == "-d" || arg == "-D") {
+                for item in ["int", "cpu_reset"] {
+                    if !machine.debug.iter().any(|debug| debug == item) {
+                        machine.debug.push(String::from(item));
+                    }
+                }
+                let generated = machine.debug_log.is_none();
+                let log = machine
+                    .debug_log
+                    .get_or_insert_with(|| monitor.dump_file("qemu", "log").display().to_string());
+                reset_log = Some((PathBuf::from(log.as_str()), generated));
+            }
+        }
+        let boot = Boot {
+            project,
+            iso: &build.iso,
+            kernel: &build.symbols,
+            work_dir: &build.work_dir,
+            args,
+            machine,
+        };
+        let mut command = backend
+            .command(&boot)
+            .map_err(|e| format!("Cannot set up {} {}", backend.name(), e))?;
+        let symbolizer = if osc_config.symbolize {
+            match Symbolizer::new(&build.symbols) {
+                Ok(symbolizer) => Some(symbolizer),
+                Err(e) => {
+                    writeln!(output, "Cannot load kernel symbols {}", e).ok();
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let timeout = if test {
+            osc_config.test_timeout.map(Duration::from_secs)
+        } else {
+            None
+        };
+        let outcome = backend::run(&mut command, symbolizer.as_ref(), timeout, monitor, output);
+        let (logged_triple_fault, frames) = match &reset_log {
+            Some((log, generated)) => {
+                let logged = backend::triple_fault_log(log);
+                // Keep a log osc made up only when it shows something.
+                if *generated && !logged.0 {
+                    fs::remove_file(log).ok();
+                }
+                logged
+            }
+            None => (false, Vec::new()),
+        };
+        match outcome {
+            Ok(Outcome::Exited(_)) | Ok(Outcome::Reset) if logged_triple_fault => {
+                writeln!(output, "---- last interrupts before the triple fault ----").ok();
+                for line in frames {
+                    writeln!(output, "{}", line).ok();
+                }
+                Err(String::from("triple fault"))
+            }
+            Ok(Outcome::Reset) => Err(String::from(
+                "triple fault (the guest reset; set accel = \"tcg\" to log its interrupts)",
+            )),
+            Ok(Outcome::Exited(status)) => Ok(backend.guest_exit_code(status)),
+            Ok(Outcome::TimedOut) => Err(format!(
+                "Timed out after {} seconds",
+                osc_config.test_timeout.unwrap_or(0)
+            )),
+            Ok(Outcome::Panicked) => Err(String::from("Guest panicked")),
+            Err(e) => Err(format!("Failed to run {} {}", backend.name(), e)),
+        }
+    }
+}
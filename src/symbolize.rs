@@ -0,0 +1,133 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use addr2line::gimli::{EndianRcSlice, RunTimeEndian};
+use addr2line::object::{Object, ObjectSection, ObjectSymbol, SectionKind};
+use addr2line::Context;
+
+/// Resolves kernel addresses printed over serial back to functions and source lines
+/// using the DWARF in `kernel.sym`.
+pub struct Symbolizer {
+    context: Context<EndianRcSlice<RunTimeEndian>>,
+    /// Function symbols sorted by address, used when an address has no DWARF info.
+    symbols: Vec<(u64, u64, String)>,
+    text: Vec<(u64, u64)>,
+}
+
+impl Symbolizer {
+    pub fn new(elf: &Path) -> Result<Self, Box<dyn Error>> {
+        let data = fs::read(elf)?;
+        let file = addr2line::object::File::parse(data.as_slice())?;
+        let context = Context::new(&file)?;
+
+        let text = file
+            .sections()
+            .filter(|section| section.kind() == SectionKind::Text && section.size() > 0)
+            .map(|section| (section.address(), section.address() + section.size()))
+            .collect();
+
+        let mut symbols: Vec<(u64, u64, String)> = file
+            .symbols()
+            .filter(|symbol| symbol.kind() == addr2line::object::SymbolKind::Text)
+            .filter_map(|symbol| {
+                let name = symbol.name().ok()?;
+                Some((symbol.address(), symbol.size(), name.to_string()))
+            })
+            .collect();
+        symbols.sort_by_key(|&(address, _, _)| address);
+
+        Ok(Self {
+            context,
+            symbols,
+            text,
+        })
+    }
+
+    fn in_text(&self, address: u64) -> bool {
+        self.text
+            .iter()
+            .any(|&(start, end)| address >= start && address < end)
+    }
+
+    /// Describes a return address as `function at file:line`, one entry per inlined frame.
+    pub fn resolve(&self, address: u64) -> Vec<String> {
+        if !self.in_text(address) {
+            return Vec::new();
+        }
+        // Return addresses point at the instruction after the call.
+        let probe = address.saturating_sub(1);
+        let mut resolved = Vec::new();
+        if let Ok(mut frames) = self.context.find_frames(probe).skip_all_loads() {
+            while let Ok(Some(frame)) = frames.next() {
+                let function = frame
+                    .function
+                    .as_ref()
+                    .and_then(|function| function.demangle().ok())
+                    .map(|name| name.into_owned())
+                    .or_else(|| self.symbol_name(probe))
+                    .unwrap_or_else(|| String::from("??"));
+                let location = frame
+                    .location
+                    .map(|location| {
+                        format!(
+                            "{}:{}",
+                            location.file.unwrap_or("??"),
+                            location.line.unwrap_or(0)
+                        )
+                    })
+                    .unwrap_or_else(|| String::from("??:0"));
+                resolved.push(format!("{} at {}", function, location));
+            }
+        }
+        if resolved.is_empty() {
+            if let Some(name) = self.symbol_name(probe) {
+                resolved.push(format!("{} at ??:0", name));
+            }
+        }
+        resolved
+    }
+
+    fn symbol_name(&self, address: u64) -> Option<String> {
+        let index = self
+            .symbols
+            .partition_point(|&(start, _, _)| start <= address);
+        let (start, size, name) = self.symbols.get(index.checked_sub(1)?)?;
+        if size != &0 && address >= start + size {
+            return None;
+        }
+        Some(addr2line::demangle_auto(name.into(), None).into_owned())
+    }
+
+    /// Lines to print under a line of kernel output, one for every code address in it.
+    pub fn annotate(&self, line: &str) -> Vec<String> {
+        let mut annotations = Vec::new();
+        for address in find_addresses(line) {
+            for (depth, frame) in self.resolve(address).iter().enumerate() {
+                if depth == 0 {
+                    annotations.push(format!("    {:#018x}: {}", address, frame));
+                } else {
+                    annotations.push(format!("    {:>18}  inlined into {}", "", frame));
+                }
+            }
+        }
+        annotations
+    }
+}
+
+/// Hex numbers with a `0x` prefix, as printed by the kernel's panic handler.
+fn find_addresses(line: &str) -> Vec<u64> {
+    let mut addresses = Vec::new();
+    let mut rest = line;
+    while let Some(index) = rest.find("0x") {
+        rest = &rest[index + 2..];
+        let digits = rest
+            .find(|char: char| !char.is_ascii_hexdigit())
+            .unwrap_or(rest.len());
+        if let Ok(address) = u64::from_str_radix(&rest[..digits], 16) {
+            addresses.push(address);
+        }
+        rest = &rest[digits..];
+    }
+    addresses
+}
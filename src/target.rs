@@ -0,0 +1,45 @@
+use std::path::{Path, PathBuf};
+
+/// The target the kernel is built for, as cargo reads `--target` and
+/// `build.target`: values ending in `.json` are target spec files, anything else
+/// is a target built into rustc.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Triple(String),
+    Spec(PathBuf),
+}
+
+impl Target {
+    /// A relative spec path is taken from `base`: the current directory for the
+    /// command line and environment, the directory holding `.cargo/` for config.
+    pub fn parse(value: &str, base: &Path) -> Self {
+        if value.ends_with(".json") {
+            Target::Spec(base.join(value))
+        } else {
+            Target::Triple(String::from(value))
+        }
+    }
+
+    /// The directory under the target dir cargo puts this target's artifacts in:
+    /// the triple, or the spec's file stem.
+    pub fn dir_name(&self) -> Option<&str> {
+        match self {
+            Target::Triple(triple) => Some(triple),
+            Target::Spec(spec) => spec.file_stem()?.to_str(),
+        }
+    }
+}
+
+/// The `--target` given to cargo in `args`, if any.
+pub fn from_args(args: &[String]) -> Option<&str> {
+    let mut target = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--target" {
+            target = args.next().map(String::as_str);
+        } else if let Some(value) = arg.strip_prefix("--target=") {
+            target = Some(value);
+        }
+    }
+    target
+}
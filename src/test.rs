@@ -0,0 +1,221 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+
+use crate::backend;
+use crate::build::{
+    artifact_libs, cargo_messages, get_first_segment, is_kernel_package, BuildError, ImageBuilder,
+    KernelBuild,
+};
+use crate::machine::MachineConfig;
+use crate::project::Project;
+use crate::runner::Runner;
+
+#[derive(Clone)]
+enum TestResult {
+    Passed,
+    Skipped,
+    Failed(String),
+}
+
+/// `osc test`: builds every test kernel with `cargo test --no-run`, then links and
+/// boots each one in its own stage directory, `jobs` at a time. With run `profiles`
+/// (`--run-profile a,b` or repeated flags) every kernel is booted once per
+/// profile. Results are printed in test-name order once all kernels have finished.
+pub fn run_tests(
+    current_dir: &Path,
+    project: &Project,
+    cargo_args: &[String],
+    jobs: Option<usize>,
+    profiles: &[String],
+) -> bool {
+    let osc_config = &project.osc_config;
+    if let Err(e) = backend::backend(project)
+        .map_err(|e| e.to_string())
+        .and_then(|backend| backend::check_tests(backend.as_ref()))
+    {
+        eprintln!("{}", e);
+        return false;
+    }
+    let mut machines: Vec<(Option<&str>, MachineConfig)> = Vec::new();
+    for name in profiles {
+        match osc_config.machine(Some(name)) {
+            Ok(machine) => machines.push((Some(name.as_str()), machine)),
+            Err(e) => {
+                eprintln!("{}", e);
+                return false;
+            }
+        }
+    }
+    if machines.is_empty() {
+        machines.push((None, osc_config.machine.clone()));
+    }
+
+    let (mut tests, libs) = match test_binaries(current_dir, project, cargo_args) {
+        Some(tests) => tests,
+        None => return false,
+    };
+    tests.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+
+    let jobs = jobs
+        .or(osc_config.test_jobs)
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |jobs| jobs.get()));
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .expect("Failed to start test threads");
+    println!(
+        "Running {} test kernels on {} machine configurations with {} jobs",
+        tests.len(),
+        machines.len(),
+        jobs
+    );
+
+    let builds: Vec<(String, Result<KernelBuild, TestResult>)> = pool.install(|| {
+        tests
+            .par_iter()
+            .map(|test| build_test(current_dir, project, test, &libs))
+            .collect()
+    });
+    let runs: Vec<_> = builds
+        .iter()
+        .flat_map(|(name, build)| {
+            machines
+                .iter()
+                .map(move |profile| (name.as_str(), build, profile))
+        })
+        .collect();
+    let results: Vec<(String, TestResult, Vec<u8>)> = pool.install(|| {
+        runs.par_iter()
+            .map(|(name, build, (profile, machine))| {
+                let name = match profile {
+                    Some(profile) => format!("{} [{}]", name, profile),
+                    None => name.to_string(),
+                };
+                match build {
+                    Ok(build) => {
+                        let (result, output) = run_test(project, build, machine.clone());
+                        (name, result, output)
+                    }
+                    Err(result) => (name, result.clone(), Vec::new()),
+                }
+            })
+            .collect()
+    });
+
+    let mut passed = 0;
+    let mut failed = 0;
+    let mut skipped = 0;
+    for (name, result, output) in &results {
+        match result {
+            TestResult::Passed => {
+                passed += 1;
+                println!("test {} ... ok", name);
+            }
+            TestResult::Skipped => {
+                skipped += 1;
+                println!("test {} ... skipped", name);
+            }
+            TestResult::Failed(reason) => {
+                failed += 1;
+                println!("test {} ... FAILED ({})", name, reason);
+                println!("---- {} output ----", name);
+                println!("{}", String::from_utf8_lossy(output));
+            }
+        }
+    }
+    println!(
+        "test kernels: {} passed; {} failed; {} skipped",
+        passed, failed, skipped
+    );
+    failed == 0
+}
+
+/// Links one test kernel into its stage directory under `target/osc`.
+fn build_test(
+    current_dir: &Path,
+    project: &Project,
+    test: &Path,
+    libs: &[PathBuf],
+) -> (String, Result<KernelBuild, TestResult>) {
+    let file_name = test.file_name().unwrap().to_str().unwrap();
+    let name = String::from(get_first_segment(file_name));
+    if project.osc_config.skip.iter().any(|skip| skip == &name) {
+        return (name, Err(TestResult::Skipped));
+    }
+
+    let profile_dir = test.parent().unwrap().parent().unwrap();
+    let stage = project
+        .target_root
+        .join("osc")
+        .join(profile_dir.file_name().unwrap())
+        .join("tests")
+        .join(file_name);
+    let builder = ImageBuilder::new(project, test)
+        .current_dir(current_dir)
+        .stage(stage)
+        .libs(libs.to_vec());
+    match builder.build() {
+        Ok(build) => (name, Ok(build)),
+        Err(e) => (name, Err(TestResult::Failed(e.to_string()))),
+    }
+}
+
+fn run_test(
+    project: &Project,
+    build: &KernelBuild,
+    machine: MachineConfig,
+) -> (TestResult, Vec<u8>) {
+    let osc_config = &project.osc_config;
+    let mut output = Vec::new();
+    let runner = Runner::new(project, build).machine(machine).test(true);
+    let result = match runner.run(&mut output) {
+        Ok(code) if code == osc_config.test_success_exit_code.or(Some(0)) => TestResult::Passed,
+        Ok(Some(code)) => TestResult::Failed(format!("exit code {}", code)),
+        Ok(None) => TestResult::Failed(String::from("killed by a signal")),
+        Err(e) => TestResult::Failed(e),
+    };
+    (result, output)
+}
+
+/// The kernel package's test executables cargo reports for `cargo test --no-run`,
+/// with the lib's static libraries it built for them. Other workspace members'
+/// tests run on the host and are left to `cargo test`.
+fn test_binaries(
+    current_dir: &Path,
+    project: &Project,
+    cargo_args: &[String],
+) -> Option<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut cargo = Command::new("cargo");
+    cargo
+        .arg("test")
+        .arg("--no-run")
+        .arg("--message-format=json-render-diagnostics")
+        .args(cargo_args)
+        .current_dir(current_dir);
+    let messages = match cargo_messages(cargo) {
+        Ok(messages) => messages,
+        Err(BuildError::Cargo) => {
+            eprintln!("Cargo test failed to build the test kernels");
+            return None;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return None;
+        }
+    };
+    let tests = messages
+        .iter()
+        .filter(|message| {
+            message["reason"] == "compiler-artifact"
+                && message["profile"]["test"] == true
+                && is_kernel_package(project, message)
+        })
+        .filter_map(|message| message["executable"].as_str())
+        .map(PathBuf::from)
+        .collect();
+    Some((tests, artifact_libs(project, &messages)))
+}